@@ -1,5 +1,11 @@
 use serde_json::*;
-use std::{io::Read, path::Path, sync::Arc};
+use std::{
+    io::Read,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::emulator::unmapped::UnmappedReport;
 
 /*
 {
@@ -55,6 +61,27 @@ pub struct MovDecrypt {
     pub payload_size_offset: u64,
 }
 
+#[derive(Clone)]
+pub struct KeyframeDecrypt {
+    pub netid: u32,
+
+    pub rva: u64,
+    pub end_rva: u64,
+
+    pub id_offset: u64,
+
+    pub x_offset: u64,
+    pub y_offset: u64,
+
+    pub health_offset: u64,
+    pub max_health_offset: u64,
+
+    pub level_offset: u64,
+
+    pub items_offset: u64,
+    pub item_count: u32,
+}
+
 #[derive(Clone)]
 pub struct Section {
     pub name: String,
@@ -63,6 +90,105 @@ pub struct Section {
     pub raw: Vec<u8>,
 }
 
+/*
+generic packets, decoded without packet specific code:
+"packets": [
+    {
+        "name": "ward_spawn",
+        "netid": 272,
+        "rva_start": "0xd603e0",
+        "rva_end": "0xd60a52",
+        "fields": [
+            { "name": "x", "offset": "0x30", "type": "f32", "capture": "write:2" },
+            { "name": "id", "offset": "0x10", "type": "u32", "capture": "write:0" },
+            { "name": "name", "offset": "0x60", "len_offset": "0x68", "type": "string" }
+        ]
+    }
+]
+types: u8, u16, u32, f32, string and buffer (pointer at `offset`, u32 length at `len_offset`)
+captures: "write:N" (Nth write covering the field, from 0), "last_write" or "final" (memory once
+the function returned, the default and the only one allowed for string and buffer)
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldType {
+    U8,
+    U16,
+    U32,
+    F32,
+    Str { len_offset: u64 },
+    Buffer { len_offset: u64 },
+}
+
+impl FieldType {
+    // bytes the field takes in the packet struct, pointer fields hold a pointer
+    pub fn size(&self) -> u64 {
+        match self {
+            FieldType::U8 => 1,
+            FieldType::U16 => 2,
+            FieldType::U32 | FieldType::F32 => 4,
+            FieldType::Str { .. } | FieldType::Buffer { .. } => 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capture {
+    NthWrite(usize),
+    LastWrite,
+    Final,
+}
+
+#[derive(Debug, Clone)]
+pub struct FieldDecrypt {
+    pub name: String,
+    pub offset: u64,
+    pub type_: FieldType,
+    pub capture: Capture,
+}
+
+impl FieldDecrypt {
+    // the field's bits out of a `size` byte write of `value` at `offset` in the packet struct.
+    // the write may start before the field, but must cover all of it: a narrower one only
+    // holds part of the value. unicorn only passes the value of writes up to 8 bytes
+    pub fn written_bits(&self, offset: u64, size: usize, value: i64) -> Option<u64> {
+        let shift = self.offset.checked_sub(offset)?;
+        if size > 8 || shift + self.type_.size() > size as u64 {
+            return None;
+        }
+
+        Some(value as u64 >> (shift * 8))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PacketDecrypt {
+    pub name: String,
+    pub netid: u32,
+
+    pub rva: u64,
+    pub end_rva: u64,
+
+    pub fields: Vec<FieldDecrypt>,
+}
+
+// bounds a single decrypt call, 0 disables a limit like it does for unicorn
+#[derive(Debug, Clone, Copy)]
+pub struct EmulationLimits {
+    pub max_instructions: usize,
+    pub timeout_ms: u64,
+}
+
+impl Default for EmulationLimits {
+    // decrypt functions run a few thousand instructions, these only catch runaways
+    fn default() -> Self {
+        Self {
+            max_instructions: 1_000_000,
+            timeout_ms: 1000,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Config {
     pub alloc1: u64,
@@ -71,11 +197,19 @@ pub struct Config {
 
     pub ward_spawn_decrypt: WardSpawnDecrypt,
     pub mov_decrypt: MovDecrypt,
+    // older patch files don't describe keyframes
+    pub keyframe_decrypt: Option<KeyframeDecrypt>,
+    pub packets: Vec<PacketDecrypt>,
 
     pub base_addr: u64,
 
     pub player_id_start: u32,
 
+    pub limits: EmulationLimits,
+    // set to map zeroed pages for unmapped reads inside the module, shared by every
+    // emulator built from this config
+    pub unmapped_report: Option<Arc<Mutex<UnmappedReport>>>,
+
     pub text: Arc<Section>,
     pub data: Arc<Section>,
     pub rdata: Arc<Section>,
@@ -189,8 +323,12 @@ impl Config {
                     json["mov_decrypt"]["payload_size_offset"].as_str().unwrap(),
                 ),
             },
+            keyframe_decrypt: Self::parse_keyframe_decrypt(&json["keyframe_decrypt"]),
+            packets: Self::parse_packet_decrypts(&json["packets"]),
             base_addr: 0x7ff76afd0000,
             player_id_start: Self::str_hex_to_u32(json["player_id_start"].as_str().unwrap()),
+            limits: EmulationLimits::default(),
+            unmapped_report: None,
             text: Section {
                 name: "text".to_string(),
                 rva: Self::str_hex_to_u64(json["text"]["rva"].as_str().unwrap()),
@@ -215,6 +353,104 @@ impl Config {
         }
     }
 
+    fn parse_keyframe_decrypt(json: &Value) -> Option<KeyframeDecrypt> {
+        if !json.is_object() {
+            return None;
+        }
+
+        Some(KeyframeDecrypt {
+            netid: json["netid"].as_u64().unwrap() as u32,
+            rva: Self::str_hex_to_u64(json["rva_start"].as_str().unwrap()),
+            end_rva: Self::str_hex_to_u64(json["rva_end"].as_str().unwrap()),
+            id_offset: Self::str_hex_to_u64(json["id_offset"].as_str().unwrap()),
+            x_offset: Self::str_hex_to_u64(json["x_offset"].as_str().unwrap()),
+            y_offset: Self::str_hex_to_u64(json["y_offset"].as_str().unwrap()),
+            health_offset: Self::str_hex_to_u64(json["health_offset"].as_str().unwrap()),
+            max_health_offset: Self::str_hex_to_u64(json["max_health_offset"].as_str().unwrap()),
+            level_offset: Self::str_hex_to_u64(json["level_offset"].as_str().unwrap()),
+            items_offset: Self::str_hex_to_u64(json["items_offset"].as_str().unwrap()),
+            item_count: Self::str_hex_to_u32(json["item_count"].as_str().unwrap()),
+        })
+    }
+
+    fn parse_packet_decrypts(json: &Value) -> Vec<PacketDecrypt> {
+        let Some(packets) = json.as_array() else {
+            return Vec::new();
+        };
+
+        packets
+            .iter()
+            .map(|packet| {
+                let name = packet["name"].as_str().unwrap().to_string();
+                let fields = packet["fields"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|field| Self::parse_field_decrypt(&name, field))
+                    .collect();
+
+                PacketDecrypt {
+                    netid: packet["netid"].as_u64().unwrap() as u32,
+                    rva: Self::str_hex_to_u64(packet["rva_start"].as_str().unwrap()),
+                    end_rva: Self::str_hex_to_u64(packet["rva_end"].as_str().unwrap()),
+                    name,
+                    fields,
+                }
+            })
+            .collect()
+    }
+
+    fn parse_field_decrypt(packet_name: &str, json: &Value) -> FieldDecrypt {
+        let name = json["name"].as_str().unwrap().to_string();
+        let len_offset = || Self::str_hex_to_u64(json["len_offset"].as_str().unwrap());
+
+        let type_ = match json["type"].as_str().unwrap() {
+            "u8" => FieldType::U8,
+            "u16" => FieldType::U16,
+            "u32" => FieldType::U32,
+            "f32" => FieldType::F32,
+            "string" => FieldType::Str {
+                len_offset: len_offset(),
+            },
+            "buffer" => FieldType::Buffer {
+                len_offset: len_offset(),
+            },
+            other => panic!(
+                "Unknown type '{}' for field {}.{}",
+                other, packet_name, name
+            ),
+        };
+
+        let capture = match json["capture"].as_str().unwrap_or("final") {
+            "final" => Capture::Final,
+            "last_write" => Capture::LastWrite,
+            capture => match capture.strip_prefix("write:").map(str::parse) {
+                Some(Ok(n)) => Capture::NthWrite(n),
+                _ => panic!(
+                    "Unknown capture '{}' for field {}.{}",
+                    capture, packet_name, name
+                ),
+            },
+        };
+
+        // the pointer is only worth reading once the function filled the buffer
+        if matches!(type_, FieldType::Str { .. } | FieldType::Buffer { .. })
+            && capture != Capture::Final
+        {
+            panic!(
+                "Field {}.{} is a pointer, only the final capture is supported",
+                packet_name, name
+            );
+        }
+
+        FieldDecrypt {
+            name,
+            offset: Self::str_hex_to_u64(json["offset"].as_str().unwrap()),
+            type_,
+            capture,
+        }
+    }
+
     pub fn str_hex_to_u64(str: &str) -> u64 {
         u64::from_str_radix(str.trim_start_matches("0x"), 16).unwrap()
     }
@@ -223,3 +459,78 @@ impl Config {
         u32::from_str_radix(str.trim_start_matches("0x"), 16).unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(json: Value) -> FieldDecrypt {
+        Config::parse_field_decrypt("ward_spawn", &json)
+    }
+
+    #[test]
+    fn captures() {
+        let x =
+            field(json!({ "name": "x", "offset": "0x30", "type": "f32", "capture": "write:2" }));
+        assert_eq!((x.offset, x.type_), (0x30, FieldType::F32));
+        assert_eq!(x.capture, Capture::NthWrite(2));
+
+        let id = field(json!({ "name": "id", "offset": "0x10", "type": "u32" }));
+        assert_eq!(id.capture, Capture::Final);
+
+        let hp = field(
+            json!({ "name": "hp", "offset": "0x10", "type": "u16", "capture": "last_write" }),
+        );
+        assert_eq!(hp.capture, Capture::LastWrite);
+
+        let name = field(json!({
+            "name": "name", "offset": "0x60", "len_offset": "0x68", "type": "string"
+        }));
+        assert_eq!(name.type_, FieldType::Str { len_offset: 0x68 });
+    }
+
+    #[test]
+    #[should_panic(expected = "only the final capture")]
+    fn pointer_fields_are_only_read_at_the_end() {
+        field(json!({
+            "name": "name", "offset": "0x60", "len_offset": "0x68", "type": "buffer",
+            "capture": "write:0"
+        }));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown capture 'write:first'")]
+    fn unknown_captures() {
+        field(json!({ "name": "x", "offset": "0x30", "type": "f32", "capture": "write:first" }));
+    }
+
+    #[test]
+    fn writes_covering_the_field() {
+        let x =
+            field(json!({ "name": "x", "offset": "0x30", "type": "u16", "capture": "write:0" }));
+
+        assert_eq!(x.written_bits(0x30, 2, 0x1234), Some(0x1234));
+        // a wider write starting before the field, the field is its third and fourth bytes
+        assert_eq!(
+            x.written_bits(0x2E, 8, 0x1122_3344_5566_7788),
+            Some(0x1122_3344_5566)
+        );
+        assert_eq!(x.written_bits(0x30, 4, -1), Some(0xFFFF_FFFF_FFFF_FFFF));
+    }
+
+    #[test]
+    fn writes_missing_part_of_the_field() {
+        let x =
+            field(json!({ "name": "x", "offset": "0x30", "type": "u32", "capture": "write:0" }));
+
+        // narrower than the field
+        assert_eq!(x.written_bits(0x30, 2, 0x1234), None);
+        // ends inside, starts inside, misses it
+        assert_eq!(x.written_bits(0x2E, 4, 0), None);
+        assert_eq!(x.written_bits(0x32, 4, 0), None);
+        assert_eq!(x.written_bits(0x28, 8, 0), None);
+        assert_eq!(x.written_bits(0x34, 4, 0), None);
+        // no value is passed for wider writes
+        assert_eq!(x.written_bits(0x30, 16, 0), None);
+    }
+}
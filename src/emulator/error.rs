@@ -0,0 +1,78 @@
+use std::fmt;
+
+// why a decrypt function didn't run to its end, `rip` is the rva of the last instruction
+#[derive(Debug, Clone)]
+pub enum EmulationError {
+    Timeout {
+        rip: u64,
+        timeout_ms: u64,
+    },
+    UnmappedRead {
+        rip: u64,
+    },
+    UnmappedWrite {
+        rip: u64,
+    },
+    InvalidInstruction {
+        rip: u64,
+    },
+    // an allocation that doesn't fit in the heap, `rip` is where the allocator was called from
+    HeapExhausted {
+        rip: u64,
+        requested: u64,
+        heap_size: usize,
+    },
+    // stopped by the instruction budget, or returned somewhere else than `end_rva`
+    EndNotReached {
+        rip: u64,
+        end_rva: u64,
+    },
+    Unicorn {
+        rip: u64,
+        message: String,
+    },
+}
+
+impl EmulationError {
+    pub fn rip(&self) -> u64 {
+        match self {
+            EmulationError::Timeout { rip, .. }
+            | EmulationError::UnmappedRead { rip }
+            | EmulationError::UnmappedWrite { rip }
+            | EmulationError::InvalidInstruction { rip }
+            | EmulationError::HeapExhausted { rip, .. }
+            | EmulationError::EndNotReached { rip, .. }
+            | EmulationError::Unicorn { rip, .. } => *rip,
+        }
+    }
+}
+
+impl fmt::Display for EmulationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmulationError::Timeout { timeout_ms, .. } => {
+                write!(f, "timed out after {} ms", timeout_ms)?
+            }
+            EmulationError::UnmappedRead { .. } => write!(f, "read from unmapped memory")?,
+            EmulationError::UnmappedWrite { .. } => write!(f, "write to unmapped memory")?,
+            EmulationError::InvalidInstruction { .. } => write!(f, "invalid instruction")?,
+            EmulationError::HeapExhausted {
+                requested,
+                heap_size,
+                ..
+            } => write!(
+                f,
+                "allocation of {:#x} bytes past the end of the {:#x} byte heap",
+                requested, heap_size
+            )?,
+            EmulationError::EndNotReached { end_rva, .. } => {
+                write!(f, "stopped before reaching rva {:#x}", end_rva)?
+            }
+            EmulationError::Unicorn { message, .. } => write!(f, "{}", message)?,
+        }
+
+        write!(f, " at rva {:#x}", self.rip())
+    }
+}
+
+impl std::error::Error for EmulationError {}
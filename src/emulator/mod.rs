@@ -1,3 +1,9 @@
 pub mod config;
+pub mod error;
 pub mod packet;
+pub mod patch;
+pub mod pool;
+pub mod record;
 pub mod stub_emulator;
+pub mod trace;
+pub mod unmapped;
@@ -52,6 +52,18 @@ pub struct WardSpawnPacket {
     pub y: i32,
 }
 
+#[derive(Debug, Clone)]
+pub struct EntityState {
+    pub timestamp: f32,
+    pub id: u32,
+    pub x: f32,
+    pub y: f32,
+    pub health: f32,
+    pub max_health: f32,
+    pub level: u8,
+    pub items: Vec<u32>,
+}
+
 #[derive(Debug, Clone)]
 pub struct PathPacket {
     pub timestamp: f32,
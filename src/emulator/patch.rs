@@ -0,0 +1,198 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use crate::parser::version::GameVersion;
+
+pub const PATCH_DIR: &str = "./patch";
+
+pub enum PatchLookup {
+    Exact(PathBuf),
+    Missing {
+        version: GameVersion,
+        nearest: Option<(GameVersion, PathBuf)>,
+        available: Vec<GameVersion>,
+    },
+}
+
+// every <major>-<minor>.patch file of `dir`, oldest first
+pub fn available_patches(dir: &Path) -> Vec<(GameVersion, PathBuf)> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let mut patches: Vec<(GameVersion, PathBuf)> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "patch"))
+        .filter_map(|path| {
+            let version = path.file_stem()?.to_str()?.parse().ok()?;
+            Some((version, path))
+        })
+        .collect();
+    patches.sort();
+
+    patches
+}
+
+// patch files only depend on major.minor, any build of the patch matches
+pub fn find_patch(dir: &Path, version: &GameVersion) -> PatchLookup {
+    let patches = available_patches(dir);
+
+    if let Some((_, path)) = patches.iter().find(|(patch, _)| patch.same_patch(version)) {
+        return PatchLookup::Exact(path.clone());
+    }
+
+    let available: Vec<GameVersion> = patches.iter().map(|(patch, _)| patch.clone()).collect();
+
+    // on a tie the older patch wins, it's the one the replay was more likely upgraded from
+    let nearest = patches
+        .iter()
+        .min_by_key(|(patch, _)| patch_distance(&available, version, patch))
+        .cloned();
+
+    PatchLookup::Missing {
+        version: version.clone(),
+        nearest,
+        available,
+    }
+}
+
+// steps from `version` to `patch` in the sorted `available` patches, the patches right before
+// and after a missing version are one step away. patch numbers aren't contiguous, a new
+// season restarts the minor number, so the list is the only reliable ordering
+fn patch_distance(available: &[GameVersion], version: &GameVersion, patch: &GameVersion) -> usize {
+    let key = |version: &GameVersion| (version.major, version.minor);
+    let (low, high) = if key(patch) < key(version) {
+        (key(patch), key(version))
+    } else {
+        (key(version), key(patch))
+    };
+
+    available
+        .iter()
+        .filter(|available| low < key(available) && key(available) < high)
+        .count()
+        + 1
+}
+
+impl fmt::Display for PatchLookup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchLookup::Exact(path) => write!(f, "patch config {}", path.display()),
+            PatchLookup::Missing {
+                version,
+                nearest,
+                available,
+            } => {
+                write!(
+                    f,
+                    "no patch file for game version {} (expected {}.patch)",
+                    version,
+                    version.patch_name()
+                )?;
+
+                match nearest {
+                    Some((patch, path)) => write!(
+                        f,
+                        ", nearest available is {} ({}, {} step(s) away in the available patches)",
+                        patch,
+                        path.display(),
+                        patch_distance(available, version, patch)
+                    )?,
+                    None => write!(f, ", no patch files found")?,
+                }
+
+                if !available.is_empty() {
+                    let available = available
+                        .iter()
+                        .map(|patch| patch.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    write!(f, ". Available: {}", available)?;
+                }
+
+                write!(
+                    f,
+                    ". Packets are decrypted with patch specific offsets, so a different patch can't be used"
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    // a fresh directory per call, tests run in parallel and so may other test runs
+    fn lookup(patches: &[&str], version: &str) -> PatchLookup {
+        static LOOKUPS: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "rofl-patches-{}-{}",
+            std::process::id(),
+            LOOKUPS.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        for patch in patches {
+            std::fs::write(dir.join(format!("{}.patch", patch)), "").unwrap();
+        }
+
+        let found = find_patch(&dir, &version.parse().unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+        found
+    }
+
+    fn nearest(lookup: &PatchLookup) -> String {
+        match lookup {
+            PatchLookup::Missing {
+                nearest: Some((patch, _)),
+                ..
+            } => patch.to_string(),
+            _ => panic!("expected a missing patch with a nearest one"),
+        }
+    }
+
+    #[test]
+    fn any_build_of_the_patch_matches() {
+        let found = lookup(&["15-3", "15-4"], "15.4.658.1234");
+        assert!(matches!(found, PatchLookup::Exact(path) if path.ends_with("15-4.patch")));
+    }
+
+    #[test]
+    fn nearest_patch_counts_available_patches_across_seasons() {
+        let patches = ["14-20", "14-24", "15-5"];
+
+        // 14.24 and 15.5 are both right next to 15.1, the older one wins
+        let missing = lookup(&patches, "15.1");
+        assert_eq!(nearest(&missing), "14.24");
+        assert!(missing.to_string().contains("1 step(s) away"));
+
+        assert_eq!(nearest(&lookup(&patches, "15.7")), "15.5");
+        assert_eq!(nearest(&lookup(&patches, "14.22")), "14.20");
+
+        let available = patches.map(|patch| patch.parse().unwrap());
+        let distance = |version: &str, patch: &str| {
+            patch_distance(
+                &available,
+                &version.parse().unwrap(),
+                &patch.parse().unwrap(),
+            )
+        };
+        assert_eq!(distance("15.1", "14.20"), 2);
+        assert_eq!(distance("15.1", "15.5"), 1);
+        assert_eq!(distance("13.1", "15.5"), 3);
+    }
+
+    #[test]
+    fn no_patch_files() {
+        let missing = lookup(&[], "15.1");
+        assert!(matches!(
+            &missing,
+            PatchLookup::Missing { nearest: None, available, .. } if available.is_empty()
+        ));
+        assert!(missing.to_string().contains("no patch files found"));
+    }
+}
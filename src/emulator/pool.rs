@@ -0,0 +1,39 @@
+use std::cell::RefCell;
+
+use anyhow::Result;
+
+use crate::emulator::{config::Config, stub_emulator::StubEmulator};
+
+thread_local! {
+    // mapping the sections takes longer than decoding hundreds of packets, every rayon
+    // worker builds its emulator once and rewinds it between packets
+    static EMULATOR: RefCell<Option<StubEmulator<'static>>> = const { RefCell::new(None) };
+}
+
+// runs `f` on this thread's emulator for `config`, then restores the emulator to its clean state
+pub fn with_emulator<T>(
+    config: &Config,
+    f: impl FnOnce(&mut StubEmulator) -> Result<T>,
+) -> Result<T> {
+    EMULATOR.with(|slot| {
+        let mut slot = slot.borrow_mut();
+
+        if !slot.as_ref().is_some_and(|emu| emu.uses_config(config)) {
+            let mut emu = StubEmulator::new(config.clone());
+            emu.setup()?;
+            emu.snapshot()?;
+            *slot = Some(emu);
+        }
+
+        let emu = slot.as_mut().unwrap();
+        let result = f(emu);
+        if let Err(e) = emu.restore() {
+            // a half restored emulator would leak this packet's state into the next ones,
+            // the next call builds a fresh one
+            *slot = None;
+            return Err(e);
+        }
+
+        result
+    })
+}
@@ -0,0 +1,68 @@
+use std::collections::BTreeMap;
+
+use serde::{Serialize, Serializer};
+
+use crate::parser::util::to_hex;
+
+fn hex<S: Serializer, T: AsRef<[u8]>>(bytes: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&to_hex(bytes.as_ref()))
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum FieldValue {
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    F32(f32),
+    Str(String),
+    #[serde(serialize_with = "hex")]
+    Buffer(Vec<u8>),
+}
+
+// a packet decoded from a `packets` entry of the patch file, fields by name
+#[derive(Debug, Clone, Serialize)]
+pub struct PacketRecord {
+    pub timestamp: f32,
+    #[serde(flatten)]
+    pub fields: BTreeMap<String, FieldValue>,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn fields_serialize_next_to_the_timestamp() {
+        let fields = BTreeMap::from([
+            ("level".to_string(), FieldValue::U8(18)),
+            ("netid".to_string(), FieldValue::U16(272)),
+            ("id".to_string(), FieldValue::U32(0x4000_0001)),
+            ("x".to_string(), FieldValue::F32(1.5)),
+            ("name".to_string(), FieldValue::Str("SightWard".to_string())),
+            (
+                "raw".to_string(),
+                FieldValue::Buffer(vec![0x0A, 0xFF, 0x00]),
+            ),
+        ]);
+        let record = PacketRecord {
+            timestamp: 90.5,
+            fields,
+        };
+
+        assert_eq!(
+            serde_json::to_value(&record).unwrap(),
+            json!({
+                "timestamp": 90.5,
+                "level": 18,
+                "netid": 272,
+                "id": 0x4000_0001,
+                "x": 1.5,
+                "name": "SightWard",
+                "raw": "0aff00",
+            })
+        );
+    }
+}
@@ -1,20 +1,26 @@
 use unicorn_engine::{
     ffi::uc_strerror,
     uc_error,
-    unicorn_const::{Arch as UnicornArch, Mode as UnicornMode},
-    HookType, Permission, RegisterX86, Unicorn,
+    unicorn_const::{Arch as UnicornArch, MemType, Mode as UnicornMode},
+    Context, HookType, Permission, RegisterX86, Unicorn,
 };
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 
 use std::{
+    collections::{hash_map::Entry, BTreeMap, HashMap},
     process::exit,
     sync::{Arc, Mutex},
+    time::Instant,
 };
 
 use crate::emulator::{
-    config::{Config, Section},
-    packet::{PathPacket, WardSpawnPacket},
+    config::{Capture, Config, FieldDecrypt, FieldType, KeyframeDecrypt, PacketDecrypt, Section},
+    error::EmulationError,
+    packet::{EntityState, PathPacket, WardSpawnPacket},
+    record::{FieldValue, PacketRecord},
+    trace::{Trace, TraceEvent},
+    unmapped::{touched_pages, UnmappedReport},
 };
 
 pub struct StubEmulator<'a> {
@@ -24,6 +30,20 @@ pub struct StubEmulator<'a> {
 
     packet_addr: u64,
     packet_size: usize,
+
+    // clean state taken right after setup, see snapshot()
+    context: Option<Context>,
+    // stack, heap and heap cursor pages, small enough to be copied whole
+    scratch: Vec<(u64, Vec<u8>)>,
+    // original content of the section pages written since the snapshot, by page address
+    dirty_pages: Arc<Mutex<HashMap<u64, Vec<u8>>>>,
+
+    // bytes of the heap mapped so far, it grows as allocations reach new pages
+    heap_mapped: Arc<Mutex<usize>>,
+    // set by the allocator stubs when an allocation doesn't fit, (caller rva, size)
+    heap_overflow: Arc<Mutex<Option<(u64, u64)>>>,
+    // zeroed pages mapped by the unmapped read hook since the snapshot
+    lazy_pages: Arc<Mutex<Vec<u64>>>,
 }
 
 impl<'a> StubEmulator<'a> {
@@ -32,8 +52,10 @@ impl<'a> StubEmulator<'a> {
     const STACK_BASE: u64 = 0x7FFFFFFF0000;
     const STACK_SIZE: usize = 0x2000;
 
-    const HEAP_BASE: u64 = 0x7FFFFFFF8000;
+    // HEAP_SIZE is mapped up front, the rest of the reservation when allocations reach it
+    const HEAP_BASE: u64 = 0x7FFF00000000;
     const HEAP_SIZE: usize = 0x2000;
+    const HEAP_MAX_SIZE: usize = 0x1000000;
 
     const HEAP_CURSOR_PTR: u64 = 0x0;
 
@@ -48,22 +70,40 @@ impl<'a> StubEmulator<'a> {
 
             packet_addr: 0,
             packet_size: 0,
+
+            context: None,
+            scratch: Vec::new(),
+            dirty_pages: Arc::new(Mutex::new(HashMap::new())),
+
+            heap_mapped: Arc::new(Mutex::new(0)),
+            heap_overflow: Arc::new(Mutex::new(None)),
+            lazy_pages: Arc::new(Mutex::new(Vec::new())),
         }
     }
 
+    // emulators built from clones of the same Config share their sections
+    pub fn uses_config(&self, config: &Config) -> bool {
+        Arc::ptr_eq(&self.config.text, &config.text)
+    }
+
     pub fn setup(&mut self) -> Result<()> {
         self.map_stack()?;
         self.map_heap()?;
         self.map_sections()?;
 
         self.patch_functions();
+        self.add_alloc_hooks()?;
+
+        if let Some(report) = self.config.unmapped_report.clone() {
+            self.add_lazy_map_hook(report)?;
+        }
 
         Ok(())
     }
 
     pub fn setup_args(&mut self, payload: &[u8]) -> Result<()> {
         self.packet_size = 0x90;
-        self.packet_addr = self.alloc(self.packet_size);
+        self.packet_addr = self.alloc(self.packet_size)?;
 
         let ptr = self.alloc_and_store(payload)?;
         let payload_ptr = self.alloc_and_store(&ptr.to_le_bytes())?;
@@ -76,14 +116,128 @@ impl<'a> StubEmulator<'a> {
         Ok(())
     }
 
-    pub fn reset(&mut self) {
-        self.set_heap_cursor(0);
+    // saves the state left by setup(), restore() brings it back after every packet.
+    // the sections are too big to copy, their pages are saved the first time they are written
+    pub fn snapshot(&mut self) -> Result<()> {
+        self.context = Some(self.uc.context_init().map_err(|e| {
+            anyhow::anyhow!(
+                "[SETUP ERROR] Failed to save context: {}",
+                Self::uc_err_to_str(e)
+            )
+        })?);
 
-        self.write_reg(
-            RegisterX86::RSP,
-            Self::STACK_BASE + (Self::STACK_SIZE - 0x100) as u64,
-        )
-        .unwrap();
+        let scratch_regions = [
+            (Self::STACK_BASE, Self::STACK_SIZE),
+            (Self::HEAP_BASE, Self::HEAP_SIZE),
+            (
+                Self::align_addr(self.rva_to_address(Self::HEAP_CURSOR_PTR)),
+                Self::PAGE_SIZE,
+            ),
+        ];
+        self.scratch = scratch_regions
+            .iter()
+            .map(|&(addr, size)| Ok((addr, self.read_buffer_on(addr, size)?)))
+            .collect::<Result<Vec<_>>>()?;
+
+        let sections = [
+            self.config.text.clone(),
+            self.config.data.clone(),
+            self.config.rdata.clone(),
+        ];
+        for sect in sections {
+            let sect_start = Self::align_addr(self.rva_to_address(sect.rva));
+            let sect_end = sect_start + Self::align_size(sect.size as usize) as u64 - 1;
+            let dirty_pages = Arc::clone(&self.dirty_pages);
+
+            // write hooks run before the write lands, the page still holds the clean content
+            self.uc
+                .add_mem_hook(
+                    HookType::MEM_WRITE,
+                    sect_start,
+                    sect_end,
+                    move |uc, _, addr, size, _| {
+                        let mut dirty_pages = dirty_pages.lock().unwrap();
+                        for page in touched_pages(addr, size, Self::PAGE_SIZE as u64) {
+                            if let Entry::Vacant(entry) = dirty_pages.entry(page) {
+                                if let Ok(content) = uc.mem_read_as_vec(page, Self::PAGE_SIZE) {
+                                    entry.insert(content);
+                                }
+                            }
+                        }
+
+                        true
+                    },
+                )
+                .map_err(|e| {
+                    anyhow::anyhow!(
+                        "[SETUP ERROR] Failed to add .{} write hook: {}",
+                        sect.name,
+                        Self::uc_err_to_str(e)
+                    )
+                })?;
+        }
+
+        Ok(())
+    }
+
+    // rewinds registers and memory to the snapshot, nothing a packet wrote leaks into the next
+    pub fn restore(&mut self) -> Result<()> {
+        let context = self
+            .context
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("[SETUP ERROR] Emulator restored without a snapshot"))?;
+        self.uc.context_restore(context).map_err(|e| {
+            anyhow::anyhow!(
+                "[SETUP ERROR] Failed to restore context: {}",
+                Self::uc_err_to_str(e)
+            )
+        })?;
+
+        // heap pages grown and zeroed pages mapped by this packet go away, the next one maps
+        // its own
+        let mut heap_mapped = self.heap_mapped.lock().unwrap();
+        if *heap_mapped > Self::HEAP_SIZE {
+            self.uc
+                .mem_unmap(
+                    Self::HEAP_BASE + Self::HEAP_SIZE as u64,
+                    *heap_mapped - Self::HEAP_SIZE,
+                )
+                .map_err(|e| {
+                    anyhow::anyhow!(
+                        "[SETUP ERROR] Failed to shrink heap: {}",
+                        Self::uc_err_to_str(e)
+                    )
+                })?;
+            *heap_mapped = Self::HEAP_SIZE;
+        }
+        drop(heap_mapped);
+
+        for page in self.lazy_pages.lock().unwrap().drain(..) {
+            self.uc.mem_unmap(page, Self::PAGE_SIZE).map_err(|e| {
+                anyhow::anyhow!(
+                    "[SETUP ERROR] Failed to unmap page at {:#x}: {}",
+                    page,
+                    Self::uc_err_to_str(e)
+                )
+            })?;
+        }
+        *self.heap_overflow.lock().unwrap() = None;
+
+        let dirty_pages: Vec<(u64, Vec<u8>)> = self.dirty_pages.lock().unwrap().drain().collect();
+        for (addr, content) in self.scratch.iter().chain(dirty_pages.iter()) {
+            self.uc.mem_write(*addr, content).map_err(|e| {
+                anyhow::anyhow!(
+                    "[SETUP ERROR] Failed to restore memory at {:#x}: {}",
+                    addr,
+                    Self::uc_err_to_str(e)
+                )
+            })?;
+        }
+
+        self.packet_addr = 0;
+        self.packet_size = 0;
+
+        Ok(())
     }
 
     pub fn call_decrypt_ward_spawn_packet(
@@ -122,7 +276,8 @@ impl<'a> StubEmulator<'a> {
         "name_len_offset": "0x68",
         */
 
-        self.uc
+        let packet_hook = self
+            .uc
             .add_mem_hook(
                 HookType::MEM_WRITE,
                 self.packet_addr,
@@ -177,12 +332,16 @@ impl<'a> StubEmulator<'a> {
                 )
             })?;
 
-        let _ = self.uc.emu_start(
-            self.rva_to_address(call_rva),
-            self.rva_to_address(end_rva),
-            0,
-            0,
-        );
+        let run = self.run(call_rva, end_rva);
+
+        // pooled emulators decode many packets, the hook only belongs to this one
+        self.uc.remove_hook(packet_hook).map_err(|e| {
+            anyhow::anyhow!(
+                "[RUNTIME ERROR] Failed to remove packet write hook: {}",
+                Self::uc_err_to_str(e)
+            )
+        })?;
+        run?;
 
         let x = x.lock().unwrap();
         let y = y.lock().unwrap();
@@ -190,14 +349,12 @@ impl<'a> StubEmulator<'a> {
         let id = id.lock().unwrap();
         let owner_id = owner_id.lock().unwrap();
 
-        let ptr = self
-            .read_ptr_on(self.packet_addr + self.config.ward_spawn_decrypt.name_offset)
-            .unwrap();
-        let size = self
-            .read_u32_on(self.packet_addr + self.config.ward_spawn_decrypt.name_len_offset)
-            .unwrap();
+        let ptr =
+            self.read_ptr_on(self.packet_addr + self.config.ward_spawn_decrypt.name_offset)?;
+        let size =
+            self.read_len_on(self.packet_addr + self.config.ward_spawn_decrypt.name_len_offset)?;
 
-        let name = self.read_str_on(ptr, size as usize).unwrap();
+        let name = self.read_str_on(ptr, size)?;
 
         Ok({
             WardSpawnPacket {
@@ -229,27 +386,327 @@ impl<'a> StubEmulator<'a> {
                 )
             })?;
 
-        let _ = self.uc.emu_start(
-            self.rva_to_address(call_rva),
-            self.rva_to_address(end_rva),
-            0,
-            0,
-        );
+        self.run(call_rva, end_rva)?;
 
-        let size = self
-            .read_u32_on(self.packet_addr + self.config.mov_decrypt.payload_size_offset)
-            .unwrap();
-        let ptr = self
-            .read_ptr_on(self.packet_addr + self.config.mov_decrypt.payload_offset)
-            .unwrap();
+        let size =
+            self.read_len_on(self.packet_addr + self.config.mov_decrypt.payload_size_offset)?;
+        let ptr = self.read_ptr_on(self.packet_addr + self.config.mov_decrypt.payload_offset)?;
 
-        let payload = self.read_buffer_on(ptr, size as usize)?;
+        let payload = self.read_buffer_on(ptr, size)?;
 
         let packet = PathPacket::parse(timestamp, payload)?;
 
         Ok(packet)
     }
 
+    pub fn call_decrypt_keyframe_state(
+        &mut self,
+        keyframe_decrypt: &KeyframeDecrypt,
+        timestamp: f32,
+    ) -> Result<EntityState> {
+        self.uc
+            .reg_write(
+                RegisterX86::RSP,
+                Self::STACK_BASE + (Self::STACK_SIZE as u64 - 0x100),
+            )
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "[SETUP ERROR] Failed to write stack pointer: {}",
+                    Self::uc_err_to_str(e)
+                )
+            })?;
+
+        self.run(keyframe_decrypt.rva, keyframe_decrypt.end_rva)?;
+
+        // unlike ward spawns, the final state of the packet struct is what we want
+        let id = self.read_u32_on(self.packet_addr + keyframe_decrypt.id_offset)?;
+        let x = f32::from_bits(self.read_u32_on(self.packet_addr + keyframe_decrypt.x_offset)?);
+        let y = f32::from_bits(self.read_u32_on(self.packet_addr + keyframe_decrypt.y_offset)?);
+        let health =
+            f32::from_bits(self.read_u32_on(self.packet_addr + keyframe_decrypt.health_offset)?);
+        let max_health = f32::from_bits(
+            self.read_u32_on(self.packet_addr + keyframe_decrypt.max_health_offset)?,
+        );
+        let level = self.read_buffer_on(self.packet_addr + keyframe_decrypt.level_offset, 1)?[0];
+
+        let items = (0..keyframe_decrypt.item_count as u64)
+            .map(|i| self.read_u32_on(self.packet_addr + keyframe_decrypt.items_offset + i * 4))
+            .collect::<Result<Vec<u32>>>()?;
+
+        Ok(EntityState {
+            timestamp,
+            id,
+            x,
+            y,
+            health,
+            max_health,
+            level,
+            items,
+        })
+    }
+
+    // records executed blocks, stub calls and every access to the stack and heap (the packet
+    // struct lives on the heap) from now on. call it after setup_args(), the hooks stay for the
+    // emulator's lifetime, so traced emulators must not go back to the pool
+    pub fn trace(&mut self) -> Result<Trace> {
+        let trace: Trace = Arc::new(Mutex::new(Vec::new()));
+
+        let base_addr = self.config.base_addr;
+        let stubs = [
+            (self.rva_to_address(self.config.skip), "skip"),
+            (self.rva_to_address(self.config.alloc1), "alloc1"),
+            (self.rva_to_address(self.config.alloc2), "alloc2"),
+        ];
+
+        let block_trace = Arc::clone(&trace);
+        self.uc
+            .add_block_hook(1, 0, move |uc, addr, size| {
+                let mut trace = block_trace.lock().unwrap();
+                trace.push(TraceEvent::Block {
+                    rva: addr.wrapping_sub(base_addr),
+                    size,
+                });
+
+                // the return address is on top of the stack when a stub is entered
+                if let Some((_, stub)) = stubs.iter().find(|(stub_addr, _)| *stub_addr == addr) {
+                    let rsp = uc.reg_read(RegisterX86::RSP).unwrap_or(0);
+                    let mut ret = [0u8; 8];
+                    let return_addr = match uc.mem_read(rsp, &mut ret) {
+                        Ok(()) => u64::from_le_bytes(ret),
+                        Err(_) => 0,
+                    };
+                    trace.push(TraceEvent::StubCall {
+                        stub,
+                        return_rva: return_addr.wrapping_sub(base_addr),
+                    });
+                }
+            })
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "[SETUP ERROR] Failed to add trace block hook: {}",
+                    Self::uc_err_to_str(e)
+                )
+            })?;
+
+        let packet = (self.packet_addr, self.packet_size as u64);
+        let regions = [
+            (Self::STACK_BASE, Self::STACK_SIZE as u64, "stack"),
+            (Self::HEAP_BASE, Self::HEAP_MAX_SIZE as u64, "heap"),
+        ];
+        for (region_base, region_size, region) in regions {
+            let mem_trace = Arc::clone(&trace);
+            self.uc
+                .add_mem_hook(
+                    HookType::MEM_WRITE | HookType::MEM_READ_AFTER,
+                    region_base,
+                    region_base + region_size - 1,
+                    move |uc, mem_type, addr, size, value| {
+                        let rip = uc.pc_read().unwrap_or(0).wrapping_sub(base_addr);
+                        let (region, offset) = if addr >= packet.0 && addr < packet.0 + packet.1 {
+                            ("packet", addr - packet.0)
+                        } else {
+                            (region, addr - region_base)
+                        };
+                        let value = value as u64;
+
+                        let event = match mem_type {
+                            MemType::WRITE => TraceEvent::Write {
+                                rip,
+                                region,
+                                offset,
+                                size,
+                                value,
+                            },
+                            _ => TraceEvent::Read {
+                                rip,
+                                region,
+                                offset,
+                                size,
+                                value,
+                            },
+                        };
+                        mem_trace.lock().unwrap().push(event);
+
+                        true
+                    },
+                )
+                .map_err(|e| {
+                    anyhow::anyhow!(
+                        "[SETUP ERROR] Failed to add {} trace hook: {}",
+                        region,
+                        Self::uc_err_to_str(e)
+                    )
+                })?;
+        }
+
+        Ok(trace)
+    }
+
+    // decodes any packet described by the patch file, see the `packets` entry in config.rs
+    pub fn call_decrypt_packet(
+        &mut self,
+        decrypt: &PacketDecrypt,
+        timestamp: f32,
+    ) -> Result<PacketRecord> {
+        let packet_addr = self.packet_addr;
+
+        // fields captured on write, by index in decrypt.fields
+        let write_fields: Vec<(usize, FieldDecrypt)> = decrypt
+            .fields
+            .iter()
+            .cloned()
+            .enumerate()
+            .filter(|(_, field)| field.capture != Capture::Final)
+            .collect();
+
+        let captured = Arc::new(Mutex::new(vec![None; decrypt.fields.len()]));
+        let captured_clone = Arc::clone(&captured);
+        let mut write_count: Vec<usize> = vec![0; decrypt.fields.len()];
+
+        let packet_hook = self
+            .uc
+            .add_mem_hook(
+                HookType::MEM_WRITE,
+                self.packet_addr,
+                self.packet_addr + self.packet_size as u64,
+                move |_, _, addr, size, value| {
+                    let offset = addr - packet_addr;
+                    let mut captured = captured_clone.lock().unwrap();
+
+                    for (i, field) in &write_fields {
+                        let Some(bits) = field.written_bits(offset, size, value) else {
+                            continue;
+                        };
+
+                        match field.capture {
+                            Capture::NthWrite(n) if write_count[*i] == n => {
+                                captured[*i] = Some(bits)
+                            }
+                            Capture::LastWrite => captured[*i] = Some(bits),
+                            _ => {}
+                        }
+                        write_count[*i] += 1;
+                    }
+
+                    true
+                },
+            )
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "[SETUP ERROR] Failed to add packet write hook: {}",
+                    Self::uc_err_to_str(e)
+                )
+            })?;
+
+        let run = self.run(decrypt.rva, decrypt.end_rva);
+
+        self.uc.remove_hook(packet_hook).map_err(|e| {
+            anyhow::anyhow!(
+                "[RUNTIME ERROR] Failed to remove packet write hook: {}",
+                Self::uc_err_to_str(e)
+            )
+        })?;
+        run?;
+
+        let captured = captured.lock().unwrap();
+        let mut fields = BTreeMap::new();
+        for (field, written) in decrypt.fields.iter().zip(captured.iter()) {
+            let value = match (field.capture, written) {
+                (Capture::Final, _) => self.read_field(field)?,
+                (_, Some(value)) => Self::field_from_bits(field, *value),
+                // never written, the field is left out rather than made up
+                (_, None) => continue,
+            };
+            fields.insert(field.name.clone(), value);
+        }
+
+        Ok(PacketRecord { timestamp, fields })
+    }
+
+    fn field_from_bits(field: &FieldDecrypt, bits: u64) -> FieldValue {
+        match field.type_ {
+            FieldType::U8 => FieldValue::U8(bits as u8),
+            FieldType::U16 => FieldValue::U16(bits as u16),
+            FieldType::F32 => FieldValue::F32(f32::from_bits(bits as u32)),
+            // pointer fields are only read from memory, see Config::parse_field_decrypt
+            FieldType::U32 | FieldType::Str { .. } | FieldType::Buffer { .. } => {
+                FieldValue::U32(bits as u32)
+            }
+        }
+    }
+
+    fn read_field(&self, field: &FieldDecrypt) -> Result<FieldValue> {
+        let addr = self.packet_addr + field.offset;
+
+        Ok(match field.type_ {
+            FieldType::U8 => FieldValue::U8(self.read_buffer_on(addr, 1)?[0]),
+            FieldType::U16 => {
+                let bytes = self.read_buffer_on(addr, 2)?;
+                FieldValue::U16(u16::from_le_bytes([bytes[0], bytes[1]]))
+            }
+            FieldType::U32 => FieldValue::U32(self.read_u32_on(addr)?),
+            FieldType::F32 => FieldValue::F32(f32::from_bits(self.read_u32_on(addr)?)),
+            FieldType::Str { len_offset } => {
+                let ptr = self.read_ptr_on(addr)?;
+                let len = self.read_len_on(self.packet_addr + len_offset)?;
+                FieldValue::Str(self.read_str_on(ptr, len)?)
+            }
+            FieldType::Buffer { len_offset } => {
+                let ptr = self.read_ptr_on(addr)?;
+                let len = self.read_len_on(self.packet_addr + len_offset)?;
+                FieldValue::Buffer(self.read_buffer_on(ptr, len)?)
+            }
+        })
+    }
+
+    // runs from `call_rva` to `end_rva` within the limits of the config
+    fn run(&mut self, call_rva: u64, end_rva: u64) -> Result<(), EmulationError> {
+        let limits = self.config.limits;
+        let end = self.rva_to_address(end_rva);
+
+        let start = Instant::now();
+        let result = self.uc.emu_start(
+            self.rva_to_address(call_rva),
+            end,
+            limits.timeout_ms * 1000,
+            limits.max_instructions,
+        );
+        let pc = self.uc.pc_read().unwrap_or(0);
+        let rip = self.address_to_rva(pc);
+
+        if let Some((rip, requested)) = self.heap_overflow.lock().unwrap().take() {
+            return Err(EmulationError::HeapExhausted {
+                rip,
+                requested,
+                heap_size: Self::HEAP_MAX_SIZE,
+            });
+        }
+
+        match result {
+            Ok(()) if pc == end => Ok(()),
+            // unicorn stops silently on both limits
+            Ok(())
+                if limits.timeout_ms > 0
+                    && start.elapsed().as_millis() >= limits.timeout_ms as u128 =>
+            {
+                Err(EmulationError::Timeout {
+                    rip,
+                    timeout_ms: limits.timeout_ms,
+                })
+            }
+            Ok(()) => Err(EmulationError::EndNotReached { rip, end_rva }),
+            Err(uc_error::READ_UNMAPPED) | Err(uc_error::FETCH_UNMAPPED) => {
+                Err(EmulationError::UnmappedRead { rip })
+            }
+            Err(uc_error::WRITE_UNMAPPED) => Err(EmulationError::UnmappedWrite { rip }),
+            Err(uc_error::INSN_INVALID) => Err(EmulationError::InvalidInstruction { rip }),
+            Err(e) => Err(EmulationError::Unicorn {
+                rip,
+                message: Self::uc_err_to_str(e),
+            }),
+        }
+    }
+
     fn map_stack(&mut self) -> Result<()> {
         self.uc
             .mem_map(
@@ -292,6 +749,34 @@ impl<'a> StubEmulator<'a> {
                     Self::uc_err_to_str(e)
                 )
             })?;
+        *self.heap_mapped.lock().unwrap() = Self::HEAP_SIZE;
+
+        // allocations made by the emulated code only move the cursor, their pages are mapped
+        // on first access. anything past the cursor wasn't handed out and stays unmapped
+        let heap_mapped = Arc::clone(&self.heap_mapped);
+        let cursor_addr = self.rva_to_address(Self::HEAP_CURSOR_PTR);
+        self.uc
+            .add_mem_hook(
+                HookType::MEM_READ_UNMAPPED | HookType::MEM_WRITE_UNMAPPED,
+                Self::HEAP_BASE,
+                Self::HEAP_BASE + Self::HEAP_MAX_SIZE as u64 - 1,
+                move |uc, _, addr, size, _| {
+                    let Ok(cursor) = Self::read_u64(uc, cursor_addr) else {
+                        return false;
+                    };
+                    if addr + size.max(1) as u64 > Self::HEAP_BASE + cursor {
+                        return false;
+                    }
+
+                    Self::grow_heap(uc, &mut heap_mapped.lock().unwrap(), cursor as usize).is_ok()
+                },
+            )
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "[SETUP ERROR] Failed to add heap growth hook: {}",
+                    Self::uc_err_to_str(e)
+                )
+            })?;
 
         self.uc
             .mem_map(
@@ -354,15 +839,27 @@ impl<'a> StubEmulator<'a> {
             .mem_write(self.rva_to_address(self.config.skip), &patch1)
             .unwrap();
 
+        // bump allocator: [rcx] = heap base + cursor, [rcx + 8] = edx, cursor += edx
+        let cursor_addr = self.rva_to_address(Self::HEAP_CURSOR_PTR).to_le_bytes();
+        let heap_base = Self::HEAP_BASE.to_le_bytes();
         let patch2 = [
-            0x53, 0x57, 0x56, 0x55, 0x41, 0x50, 0x41, 0x51, 0x41, 0x52, 0x41, 0x53, 0x41, 0x54,
-            0x41, 0x55, 0x41, 0x56, 0x41, 0x57, 0x48, 0xB8, 0x00, 0x00, 0xFD, 0x6A, 0xF7, 0x7F,
-            0x00, 0x00, 0x48, 0x8B, 0x18, 0x48, 0xB8, 0x00, 0x80, 0xFF, 0xFF, 0xFF, 0x7F, 0x00,
-            0x00, 0x48, 0x8D, 0x04, 0x18, 0x48, 0x89, 0x01, 0x89, 0x51, 0x08, 0x01, 0xD3, 0x48,
-            0xB8, 0x00, 0x00, 0xFD, 0x6A, 0xF7, 0x7F, 0x00, 0x00, 0x89, 0x18, 0x41, 0x5F, 0x41,
-            0x5E, 0x41, 0x5D, 0x41, 0x5C, 0x41, 0x5B, 0x41, 0x5A, 0x41, 0x59, 0x41, 0x58, 0x5D,
-            0x5E, 0x5F, 0x5B, 0xC3,
-        ];
+            &[
+                0x53, 0x57, 0x56, 0x55, 0x41, 0x50, 0x41, 0x51, 0x41, 0x52, 0x41, 0x53, 0x41, 0x54,
+                0x41, 0x55, 0x41, 0x56, 0x41, 0x57, 0x48, 0xB8,
+            ][..],
+            &cursor_addr,
+            &[0x48, 0x8B, 0x18, 0x48, 0xB8],
+            &heap_base,
+            &[
+                0x48, 0x8D, 0x04, 0x18, 0x48, 0x89, 0x01, 0x89, 0x51, 0x08, 0x01, 0xD3, 0x48, 0xB8,
+            ],
+            &cursor_addr,
+            &[
+                0x89, 0x18, 0x41, 0x5F, 0x41, 0x5E, 0x41, 0x5D, 0x41, 0x5C, 0x41, 0x5B, 0x41, 0x5A,
+                0x41, 0x59, 0x41, 0x58, 0x5D, 0x5E, 0x5F, 0x5B, 0xC3,
+            ],
+        ]
+        .concat();
 
         self.uc
             .mem_write(self.rva_to_address(self.config.alloc1), &patch2)
@@ -389,15 +886,136 @@ impl<'a> StubEmulator<'a> {
             .expect("Failed to write memory");
     }
 
-    fn alloc(&mut self, size: usize) -> u64 {
+    fn alloc(&mut self, size: usize) -> Result<u64> {
         let heap_cursor = self.get_heap_cursor();
+        let end = heap_cursor as usize + size;
+        if end > Self::HEAP_MAX_SIZE {
+            bail!(
+                "[SETUP ERROR] Allocation of {:#x} bytes past the end of the {:#x} byte heap",
+                size,
+                Self::HEAP_MAX_SIZE
+            );
+        }
+
+        Self::grow_heap(&mut self.uc, &mut self.heap_mapped.lock().unwrap(), end).map_err(|e| {
+            anyhow::anyhow!(
+                "[SETUP ERROR] Failed to grow heap: {}",
+                Self::uc_err_to_str(e)
+            )
+        })?;
+
         let ptr = Self::HEAP_BASE + heap_cursor;
         self.set_heap_cursor(heap_cursor + size as u64);
-        ptr
+        Ok(ptr)
+    }
+
+    // maps the heap up to `size` bytes, `mapped` is how much of it already is
+    fn grow_heap(
+        uc: &mut Unicorn<'_, ()>,
+        mapped: &mut usize,
+        size: usize,
+    ) -> Result<(), uc_error> {
+        let size = Self::align_size(size).min(Self::HEAP_MAX_SIZE);
+        if size > *mapped {
+            uc.mem_map(
+                Self::HEAP_BASE + *mapped as u64,
+                size - *mapped,
+                Permission::READ | Permission::WRITE,
+            )?;
+            *mapped = size;
+        }
+
+        Ok(())
+    }
+
+    fn read_u64(uc: &Unicorn<'_, ()>, addr: u64) -> Result<u64, uc_error> {
+        let mut buffer = [0u8; 8];
+        uc.mem_read(addr, &mut buffer)?;
+        Ok(u64::from_le_bytes(buffer))
+    }
+
+    // the allocator stubs have no bounds check, catch oversized requests before they run
+    fn add_alloc_hooks(&mut self) -> Result<()> {
+        let base_addr = self.config.base_addr;
+        let cursor_addr = self.rva_to_address(Self::HEAP_CURSOR_PTR);
+
+        for stub in [self.config.alloc1, self.config.alloc2] {
+            let stub_addr = self.rva_to_address(stub);
+            let heap_overflow = Arc::clone(&self.heap_overflow);
+
+            self.uc
+                .add_code_hook(stub_addr, stub_addr, move |uc, _, _| {
+                    let cursor = Self::read_u64(uc, cursor_addr).unwrap_or(0);
+                    let requested = uc.reg_read(RegisterX86::RDX).unwrap_or(0) & 0xFFFFFFFF;
+                    if cursor + requested <= Self::HEAP_MAX_SIZE as u64 {
+                        return;
+                    }
+
+                    let rsp = uc.reg_read(RegisterX86::RSP).unwrap_or(0);
+                    let return_addr = Self::read_u64(uc, rsp).unwrap_or(0);
+                    *heap_overflow.lock().unwrap() =
+                        Some((return_addr.wrapping_sub(base_addr), requested));
+                    let _ = uc.emu_stop();
+                })
+                .map_err(|e| {
+                    anyhow::anyhow!(
+                        "[SETUP ERROR] Failed to add allocator hook: {}",
+                        Self::uc_err_to_str(e)
+                    )
+                })?;
+        }
+
+        Ok(())
+    }
+
+    // answers reads of unmapped pages inside the game module with zeroed, read only pages,
+    // and records each of them in `report`. writes and reads outside the module still fail
+    fn add_lazy_map_hook(&mut self, report: Arc<Mutex<UnmappedReport>>) -> Result<()> {
+        let base_addr = self.config.base_addr;
+        let image_end = [&self.config.text, &self.config.data, &self.config.rdata]
+            .iter()
+            .map(|sect| {
+                Self::align_addr(self.rva_to_address(sect.rva))
+                    + Self::align_size(sect.size as usize) as u64
+            })
+            .max()
+            .unwrap();
+        let lazy_pages = Arc::clone(&self.lazy_pages);
+
+        self.uc
+            .add_mem_hook(
+                HookType::MEM_READ_UNMAPPED,
+                base_addr,
+                image_end - 1,
+                move |uc, _, addr, size, _| {
+                    let mut lazy_pages = lazy_pages.lock().unwrap();
+                    for page in touched_pages(addr, size, Self::PAGE_SIZE as u64) {
+                        if uc.mem_map(page, Self::PAGE_SIZE, Permission::READ).is_ok() {
+                            lazy_pages.push(page);
+                        }
+                    }
+
+                    let rip = uc.pc_read().unwrap_or(0).wrapping_sub(base_addr);
+                    report
+                        .lock()
+                        .unwrap()
+                        .record(rip, addr.wrapping_sub(base_addr), size);
+
+                    true
+                },
+            )
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "[SETUP ERROR] Failed to add unmapped read hook: {}",
+                    Self::uc_err_to_str(e)
+                )
+            })?;
+
+        Ok(())
     }
 
     fn alloc_and_store(&mut self, data: &[u8]) -> Result<u64> {
-        let ptr = self.alloc(data.len());
+        let ptr = self.alloc(data.len())?;
         self.uc.mem_write(ptr, data).map_err(|e| {
             anyhow::anyhow!(
                 "[SETUP ERROR] Failed to store on heap: {}",
@@ -425,7 +1043,8 @@ impl<'a> StubEmulator<'a> {
                 Self::uc_err_to_str(e)
             )
         })?;
-        Ok(String::from_utf8(buffer).unwrap())
+        String::from_utf8(buffer)
+            .map_err(|e| anyhow::anyhow!("[RUNTIME ERROR] String is not valid UTF-8: {}", e))
     }
 
     fn read_buffer_on(&self, addr: u64, size: usize) -> Result<Vec<u8>> {
@@ -450,6 +1069,21 @@ impl<'a> StubEmulator<'a> {
         Ok(u32::from_le_bytes(buffer))
     }
 
+    // length of a packet string or buffer. it comes from emulated memory, a garbage value
+    // must not allocate gigabytes, and nothing the decrypt wrote can be larger than the heap
+    fn read_len_on(&self, addr: u64) -> Result<usize> {
+        let len = self.read_u32_on(addr)? as usize;
+        if len > Self::HEAP_MAX_SIZE {
+            return Err(anyhow::anyhow!(
+                "[RUNTIME ERROR] Length {:#x} read at {:#x} is larger than the heap ({:#x})",
+                len,
+                addr,
+                Self::HEAP_MAX_SIZE
+            ));
+        }
+        Ok(len)
+    }
+
     fn read_ptr_on(&self, addr: u64) -> Result<u64> {
         let mut buffer = [0u8; 8];
         self.uc.mem_read(addr, &mut buffer).map_err(|e| {
@@ -466,7 +1100,7 @@ impl<'a> StubEmulator<'a> {
     }
 
     fn address_to_rva(&self, addr: u64) -> u64 {
-        addr - self.config.base_addr
+        addr.wrapping_sub(self.config.base_addr)
     }
 
     fn align_addr(addr: u64) -> u64 {
@@ -485,3 +1119,244 @@ impl<'a> StubEmulator<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{
+        config::{EmulationLimits, MovDecrypt, WardSpawnDecrypt},
+        pool::with_emulator,
+    };
+
+    const BASE_ADDR: u64 = 0x7ff76afd0000;
+    // the first page of the module holds the heap cursor, the sections come after it
+    const CODE_RVA: u64 = 0x1000;
+    const SKIP_RVA: u64 = 0x1800;
+    const ALLOC_RVA: u64 = 0x1900;
+    const DATA_RVA: u64 = 0x2000;
+    const RDATA_RVA: u64 = 0x4000;
+
+    fn section(name: &str, rva: u64, mut raw: Vec<u8>) -> Arc<Section> {
+        raw.resize(StubEmulator::PAGE_SIZE, 0);
+        Arc::new(Section {
+            name: name.to_string(),
+            rva,
+            size: raw.len() as u64,
+            raw,
+        })
+    }
+
+    // a small module with `code` at CODE_RVA, .data filled with 0xAB
+    fn config(code: &[u8], limits: EmulationLimits) -> Config {
+        Config {
+            alloc1: ALLOC_RVA,
+            alloc2: ALLOC_RVA + 0x100,
+            skip: SKIP_RVA,
+            ward_spawn_decrypt: WardSpawnDecrypt {
+                netid: 0,
+                rva: CODE_RVA,
+                end_rva: CODE_RVA,
+                id_offset: 0,
+                owner_id_offset: 0,
+                name_offset: 0,
+                name_len_offset: 0,
+                x_offset: 0,
+                x_write_count: 0,
+                y_offset: 0,
+                y_write_count: 0,
+            },
+            mov_decrypt: MovDecrypt {
+                netid: 0,
+                rva: CODE_RVA,
+                end_rva: CODE_RVA,
+                payload_offset: 0,
+                payload_size_offset: 0,
+            },
+            keyframe_decrypt: None,
+            packets: Vec::new(),
+            base_addr: BASE_ADDR,
+            player_id_start: 0,
+            limits,
+            unmapped_report: None,
+            text: section("text", CODE_RVA, code.to_vec()),
+            data: section("data", DATA_RVA, vec![0xAB; StubEmulator::PAGE_SIZE]),
+            rdata: section("rdata", RDATA_RVA, Vec::new()),
+        }
+    }
+
+    // jmp $
+    const SPIN: [u8; 2] = [0xEB, 0xFE];
+
+    // mov edx, `size`; call alloc1
+    fn alloc_code(size: u32) -> Vec<u8> {
+        let call_rel = (ALLOC_RVA - (CODE_RVA + 10)) as u32;
+        [
+            &[0xBA][..],
+            &size.to_le_bytes(),
+            &[0xE8],
+            &call_rel.to_le_bytes(),
+        ]
+        .concat()
+    }
+
+    fn emulator(config: Config) -> StubEmulator<'static> {
+        let mut emu = StubEmulator::new(config);
+        emu.setup().unwrap();
+        emu
+    }
+
+    // rcx, heap cursor, heap bytes mapped and the first bytes of .data
+    fn state(emu: &mut StubEmulator) -> Result<(u64, u64, usize, Vec<u8>)> {
+        Ok((
+            emu.uc.reg_read(RegisterX86::RCX).unwrap(),
+            emu.get_heap_cursor(),
+            *emu.heap_mapped.lock().unwrap(),
+            emu.read_buffer_on(BASE_ADDR + DATA_RVA, 8)?,
+        ))
+    }
+
+    #[test]
+    fn restore_brings_back_the_snapshot() {
+        // mov rcx, 0x1234; mov rax, <.data>; mov qword ptr [rax], 0x12345678
+        let code = [
+            &[0x48, 0xC7, 0xC1, 0x34, 0x12, 0x00, 0x00][..],
+            &[0x48, 0xB8],
+            &(BASE_ADDR + DATA_RVA).to_le_bytes(),
+            &[0x48, 0xC7, 0x00, 0x78, 0x56, 0x34, 0x12],
+        ]
+        .concat();
+        let end_rva = CODE_RVA + code.len() as u64;
+        let config = config(&code, EmulationLimits::default());
+
+        let clean = with_emulator(&config, state).unwrap();
+        assert_eq!(clean.3, [0xAB; 8]);
+
+        let dirty = with_emulator(&config, |emu| {
+            // more payload than the initial heap holds
+            emu.setup_args(&[1; 0x3000])?;
+            emu.run(CODE_RVA, end_rva)?;
+            state(emu)
+        })
+        .unwrap();
+        assert_eq!(dirty.0, 0x1234);
+        assert!(dirty.1 > clean.1);
+        assert!(dirty.2 > StubEmulator::HEAP_SIZE);
+        assert_eq!(dirty.3, 0x12345678u64.to_le_bytes());
+
+        let restored = with_emulator(&config, |emu| {
+            let past_heap = StubEmulator::HEAP_BASE + StubEmulator::HEAP_SIZE as u64;
+            assert!(emu.read_buffer_on(past_heap, 1).is_err());
+            state(emu)
+        })
+        .unwrap();
+        assert_eq!(restored, clean);
+    }
+
+    #[test]
+    fn instruction_limit_stops_endless_loops() {
+        let limits = EmulationLimits {
+            max_instructions: 1000,
+            timeout_ms: 0,
+        };
+        let mut emu = emulator(config(&SPIN, limits));
+
+        let error = emu.run(CODE_RVA, CODE_RVA + 2).unwrap_err();
+        assert!(matches!(
+            error,
+            EmulationError::EndNotReached { rip: CODE_RVA, end_rva } if end_rva == CODE_RVA + 2
+        ));
+        assert!(error.to_string().ends_with("at rva 0x1000"));
+    }
+
+    #[test]
+    fn time_limit_stops_endless_loops() {
+        let limits = EmulationLimits {
+            max_instructions: 0,
+            timeout_ms: 50,
+        };
+        let mut emu = emulator(config(&SPIN, limits));
+
+        assert!(matches!(
+            emu.run(CODE_RVA, CODE_RVA + 2),
+            Err(EmulationError::Timeout {
+                rip: CODE_RVA,
+                timeout_ms: 50
+            })
+        ));
+    }
+
+    #[test]
+    fn allocations_past_the_heap_stop_at_the_caller() {
+        let code = alloc_code(0x2000000);
+        let end_rva = CODE_RVA + code.len() as u64;
+        let mut emu = emulator(config(&code, EmulationLimits::default()));
+
+        // the rva is the return address of the allocator call
+        assert!(matches!(
+            emu.run(CODE_RVA, end_rva),
+            Err(EmulationError::HeapExhausted {
+                rip,
+                requested: 0x2000000,
+                heap_size: StubEmulator::HEAP_MAX_SIZE,
+            }) if rip == end_rva
+        ));
+    }
+
+    #[test]
+    fn heap_grows_up_to_its_reservation() {
+        // allocate 1 MiB, then mov rax, [rcx]; mov byte ptr [rax + 0xfffff], 1
+        let code = [
+            &alloc_code(0x100000)[..],
+            &[0x48, 0x8B, 0x01],
+            &[0xC6, 0x80, 0xFF, 0xFF, 0x0F, 0x00, 0x01],
+        ]
+        .concat();
+        let end_rva = CODE_RVA + code.len() as u64;
+        let mut emu = emulator(config(&code, EmulationLimits::default()));
+        assert_eq!(*emu.heap_mapped.lock().unwrap(), StubEmulator::HEAP_SIZE);
+
+        // the allocator stores the pointer at rcx
+        emu.write_reg(RegisterX86::RCX, StubEmulator::HEAP_BASE)
+            .unwrap();
+        emu.run(CODE_RVA, end_rva).unwrap();
+        assert_eq!(*emu.heap_mapped.lock().unwrap(), 0x100000);
+        let last_byte = StubEmulator::HEAP_BASE + 0xFFFFF;
+        assert_eq!(emu.read_buffer_on(last_byte, 1).unwrap(), [1]);
+
+        emu.alloc(StubEmulator::HEAP_MAX_SIZE - 0x100000).unwrap();
+        assert_eq!(
+            *emu.heap_mapped.lock().unwrap(),
+            StubEmulator::HEAP_MAX_SIZE
+        );
+        assert!(emu.alloc(1).is_err());
+    }
+
+    #[test]
+    fn lazy_mapped_reads_are_reported() {
+        // the page between .data and .rdata: mov rax, <page>; mov rax, [rax]
+        let code = [
+            &[0x48, 0xB8][..],
+            &(BASE_ADDR + 0x3010).to_le_bytes(),
+            &[0x48, 0x8B, 0x00],
+        ]
+        .concat();
+        let end_rva = CODE_RVA + code.len() as u64;
+        let report = Arc::new(Mutex::new(UnmappedReport::default()));
+        let mut config = config(&code, EmulationLimits::default());
+        config.unmapped_report = Some(Arc::clone(&report));
+
+        let mut emu = emulator(config);
+        emu.snapshot().unwrap();
+        emu.run(CODE_RVA, end_rva).unwrap();
+        assert_eq!(emu.uc.reg_read(RegisterX86::RAX).unwrap(), 0);
+        assert_eq!(
+            report.lock().unwrap().to_string(),
+            "1 unmapped read(s) answered with zeroed pages:\n  \
+             rva 0x100a read 8 byte(s) at rva 0x3010, 1 time(s)"
+        );
+
+        // the zeroed page only lives until the next packet
+        emu.restore().unwrap();
+        assert!(emu.read_buffer_on(BASE_ADDR + 0x3010, 1).is_err());
+    }
+}
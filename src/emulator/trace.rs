@@ -0,0 +1,57 @@
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use serde::{Serialize, Serializer};
+
+fn hex<S: Serializer>(value: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&format_args!("{:#x}", value))
+}
+
+// one line of a trace file. code addresses are rvas, memory accesses are relative to the
+// start of their region ("packet", "heap" or "stack")
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TraceEvent {
+    Block {
+        #[serde(serialize_with = "hex")]
+        rva: u64,
+        size: u32,
+    },
+    // calls into the functions replaced by patch_functions()
+    StubCall {
+        stub: &'static str,
+        #[serde(serialize_with = "hex")]
+        return_rva: u64,
+    },
+    Read {
+        #[serde(serialize_with = "hex")]
+        rip: u64,
+        region: &'static str,
+        #[serde(serialize_with = "hex")]
+        offset: u64,
+        size: usize,
+        #[serde(serialize_with = "hex")]
+        value: u64,
+    },
+    Write {
+        #[serde(serialize_with = "hex")]
+        rip: u64,
+        region: &'static str,
+        #[serde(serialize_with = "hex")]
+        offset: u64,
+        size: usize,
+        #[serde(serialize_with = "hex")]
+        value: u64,
+    },
+}
+
+pub type Trace = Arc<Mutex<Vec<TraceEvent>>>;
+
+// JSON lines, one event per line in execution order
+pub fn write_trace<W: Write>(events: &[TraceEvent], mut writer: W) -> io::Result<()> {
+    for event in events {
+        serde_json::to_writer(&mut writer, event)?;
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
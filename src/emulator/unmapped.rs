@@ -0,0 +1,85 @@
+use std::{collections::BTreeMap, fmt};
+
+// reads of unmapped memory inside the game module that the lazy mapping hook answered with
+// zeroed pages. they point at data the patch file doesn't ship, and at decrypt results that
+// may be wrong because of it
+#[derive(Debug, Default)]
+pub struct UnmappedReport {
+    // (rva of the instruction, rva read) -> (largest access size, times seen)
+    accesses: BTreeMap<(u64, u64), (usize, usize)>,
+}
+
+impl UnmappedReport {
+    pub fn record(&mut self, rip: u64, addr: u64, size: usize) {
+        let access = self.accesses.entry((rip, addr)).or_insert((0, 0));
+        access.0 = access.0.max(size);
+        access.1 += 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accesses.is_empty()
+    }
+}
+
+// start of every `page_size` page an access of `size` bytes at `addr` touches, a read
+// straddling two pages may only miss the second one
+pub fn touched_pages(addr: u64, size: usize, page_size: u64) -> impl Iterator<Item = u64> {
+    let first_page = addr & !(page_size - 1);
+    let last_page = (addr + size.max(1) as u64 - 1) & !(page_size - 1);
+    (first_page..=last_page).step_by(page_size as usize)
+}
+
+impl fmt::Display for UnmappedReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} unmapped read(s) answered with zeroed pages:",
+            self.accesses.len()
+        )?;
+        for ((rip, addr), (size, count)) in &self.accesses {
+            write!(
+                f,
+                "\n  rva {:#x} read {} byte(s) at rva {:#x}, {} time(s)",
+                rip, size, addr, count
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_are_merged_by_instruction_and_address() {
+        let mut report = UnmappedReport::default();
+        assert!(report.is_empty());
+
+        report.record(0x1000, 0x3010, 4);
+        report.record(0x1000, 0x3010, 8);
+        report.record(0x1000, 0x3010, 2);
+        report.record(0x1020, 0x3010, 1);
+        assert!(!report.is_empty());
+        assert_eq!(report.accesses[&(0x1000, 0x3010)], (8, 3));
+
+        assert_eq!(
+            report.to_string(),
+            "2 unmapped read(s) answered with zeroed pages:\n  \
+             rva 0x1000 read 8 byte(s) at rva 0x3010, 3 time(s)\n  \
+             rva 0x1020 read 1 byte(s) at rva 0x3010, 1 time(s)"
+        );
+    }
+
+    #[test]
+    fn pages_of_an_access() {
+        let pages = |addr, size| touched_pages(addr, size, 0x1000).collect::<Vec<_>>();
+
+        assert_eq!(pages(0x3010, 8), [0x3000]);
+        assert_eq!(pages(0x3FF8, 8), [0x3000]);
+        assert_eq!(pages(0x3FFC, 8), [0x3000, 0x4000]);
+        assert_eq!(pages(0x4000, 0x1001), [0x4000, 0x5000]);
+        // unicorn reports some accesses with no size
+        assert_eq!(pages(0x4FFF, 0), [0x4000]);
+    }
+}
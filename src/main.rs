@@ -2,28 +2,49 @@ use std::{
     collections::HashMap,
     env,
     fs::File,
-    io::Write,
+    io::{self, BufReader, BufWriter, Write},
     path::{Path, PathBuf},
     sync::{Arc, Mutex},
 };
 
+use anyhow::{bail, Result};
 use serde_json::{json, Value};
 
 use chrono::{format::format, Local};
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use colored::*;
-use emulator::packet::{PathPacket, PosKey, WardSpawnPacket};
+use emulator::packet::{EntityState, PathPacket, PosKey, WardSpawnPacket};
 use fern::*;
-use log::{info, warn, LevelFilter};
+use log::{error, info, warn, LevelFilter};
 use rayon::prelude::*;
 
 mod emulator;
 mod parser;
+mod roles;
 
-use crate::emulator::{config::Config, stub_emulator::StubEmulator};
-use crate::parser::{metadata::Metadata, parser::get_blocks_with_id, util::read_file};
+use crate::emulator::{
+    config::{Config, EmulationLimits, KeyframeDecrypt},
+    patch::{find_patch, PatchLookup, PATCH_DIR},
+    pool::with_emulator,
+    record::PacketRecord,
+    stub_emulator::StubEmulator,
+    trace::write_trace,
+};
+use crate::parser::{
+    demux::BlockDemux,
+    dump::{dump_blocks, DumpFilter, DumpFormat},
+    error::ParseError,
+    inspect::ReplayStats,
+    keyframe::Keyframe,
+    metadata::{GameOutcome, Metadata},
+    parser::{get_keyframes, BlockPayload, ParseOptions},
+    rewrite::{rewrite, RewriteOptions},
+    version::GameVersion,
+};
+use crate::roles::infer_roles;
 
-const BATCH_SIZE: usize = 100;
+// fallback for `rewrite --salt`, keeps the salt out of the shell history
+const ANONYMIZE_SALT_VAR: &str = "ROFL_ANONYMIZE_SALT";
 
 fn setup_logger() -> Result<(), fern::InitError> {
     Dispatch::new()
@@ -55,6 +76,39 @@ pub struct Cli {
     parsing: Parsing,
 }
 
+// emulator options shared by the commands that decrypt packets
+#[derive(Debug, Args)]
+struct EmulationArgs {
+    #[clap(
+        long,
+        help = "Instructions a single decrypt call may run before it is aborted, 0 for no limit"
+    )]
+    max_instructions: Option<usize>,
+    #[clap(
+        long,
+        help = "Milliseconds a single decrypt call may run before it is aborted, 0 for no limit"
+    )]
+    emu_timeout: Option<u64>,
+    #[clap(
+        long,
+        help = "Answer reads of unmapped module memory with zeroed pages and report them"
+    )]
+    lazy_map: bool,
+}
+
+// the limits of every decrypt call, and whether to lazy map unmapped module reads
+impl From<EmulationArgs> for (EmulationLimits, bool) {
+    fn from(args: EmulationArgs) -> Self {
+        let default = EmulationLimits::default();
+        let limits = EmulationLimits {
+            max_instructions: args.max_instructions.unwrap_or(default.max_instructions),
+            timeout_ms: args.emu_timeout.unwrap_or(default.timeout_ms),
+        };
+
+        (limits, args.lazy_map)
+    }
+}
+
 #[derive(Debug, Subcommand)]
 enum Parsing {
     Folder {
@@ -62,8 +116,25 @@ enum Parsing {
         replay_folder: String,
         #[clap(short, long, help = "Path to output folder")]
         output_folder: String,
-        #[clap(short, long, help = "Path to to patch file")]
-        patch_version: String,
+        #[clap(
+            short,
+            long,
+            help = "Game version of the replays, e.g. 15.4 (loads ./patch/15-4.patch)"
+        )]
+        patch_version: GameVersion,
+        #[clap(long, help = "Only parse blocks after this timestamp (seconds)")]
+        start: Option<f32>,
+        #[clap(long, help = "Only parse blocks before this timestamp (seconds)")]
+        end: Option<f32>,
+        #[clap(
+            long,
+            help = "Keep going past damaged chunks and report the lost time ranges"
+        )]
+        recover: bool,
+        #[clap(long, help = "Skip games that ended in a remake")]
+        exclude_remakes: bool,
+        #[clap(flatten)]
+        emulation: EmulationArgs,
     },
     File {
         #[clap(short, long)]
@@ -72,43 +143,236 @@ enum Parsing {
         output_file: String,
         #[clap(short, long)]
         debug: bool,
+        #[clap(long, help = "Only parse blocks after this timestamp (seconds)")]
+        start: Option<f32>,
+        #[clap(long, help = "Only parse blocks before this timestamp (seconds)")]
+        end: Option<f32>,
+        #[clap(
+            long,
+            help = "Keep going past damaged chunks and report the lost time ranges"
+        )]
+        recover: bool,
+        #[clap(long, help = "Decompress and parse chunks on all cores")]
+        parallel: bool,
+        #[clap(flatten)]
+        emulation: EmulationArgs,
+    },
+    Dump {
+        #[clap(short, long)]
+        replay_file: String,
+        #[clap(short, long)]
+        output_file: String,
+        #[clap(long, default_value = "jsonl", help = "Output format: jsonl or bin")]
+        format: DumpFormat,
+        #[clap(long = "packet", help = "Only dump this packet id, can be repeated")]
+        packet_ids: Vec<u16>,
+        #[clap(
+            long = "param",
+            parse(try_from_str = parse_number),
+            help = "Only dump blocks with this param (entity id), can be repeated"
+        )]
+        params: Vec<u32>,
+        #[clap(long, help = "Only dump blocks after this timestamp (seconds)")]
+        start: Option<f32>,
+        #[clap(long, help = "Only dump blocks before this timestamp (seconds)")]
+        end: Option<f32>,
+    },
+    Trace {
+        #[clap(short, long)]
+        replay_file: String,
+        #[clap(short, long, help = "Path to the trace file (JSON lines)")]
+        output_file: String,
+        #[clap(long, help = "Packet id of the block to decrypt")]
+        packet: u16,
+        #[clap(
+            long,
+            default_value = "0",
+            help = "Which block of that packet id to decrypt, from 0 in game order"
+        )]
+        index: usize,
+        #[clap(flatten)]
+        emulation: EmulationArgs,
+    },
+    Info {
+        #[clap(
+            short,
+            long = "replay",
+            required = true,
+            help = "Replay file or folder of replays, can be repeated"
+        )]
+        replay_paths: Vec<String>,
+        #[clap(long, help = "Print one JSON object per replay instead of a summary")]
+        json: bool,
+    },
+    Inspect {
+        #[clap(short, long)]
+        replay_file: String,
+        #[clap(long, help = "Print JSON instead of tables")]
+        json: bool,
     },
+    Rewrite {
+        #[clap(short, long)]
+        replay_file: String,
+        #[clap(short, long)]
+        output_file: String,
+        #[clap(long, help = "Drop blocks before this timestamp (seconds)")]
+        start: Option<f32>,
+        #[clap(long, help = "Drop blocks after this timestamp (seconds)")]
+        end: Option<f32>,
+        #[clap(long = "drop-packet", help = "Packet id to remove, can be repeated")]
+        drop_packet: Vec<u16>,
+        #[clap(
+            long,
+            help = "Replace player names and Riot ids with pseudonyms keyed with the salt"
+        )]
+        anonymize: bool,
+        #[clap(
+            long,
+            help = "Secret salt of the pseudonyms, defaults to the ROFL_ANONYMIZE_SALT variable"
+        )]
+        salt: Option<String>,
+    },
+}
+
+fn open_replay(replay_path: &str) -> io::Result<BufReader<File>> {
+    File::open(replay_path).map(BufReader::new)
 }
 
-fn get_replay_info(file: Vec<u8>, metadata: &Metadata, config: &Config) -> Value {
+fn write_json(path: &Path, value: &Value) -> io::Result<()> {
+    File::create(path)?.write_all(value.to_string().as_bytes())
+}
+
+fn time_range(start: Option<f32>, end: Option<f32>) -> Option<(f32, f32)> {
+    if start.is_none() && end.is_none() {
+        return None;
+    }
+
+    Some((start.unwrap_or(0.0), end.unwrap_or(f32::INFINITY)))
+}
+
+fn get_replay_info(
+    replay_path: &str,
+    metadata: &Metadata,
+    config: &Config,
+    options: ParseOptions,
+) -> Result<Value> {
     let mut game = json!({
-        "metadata": metadata.clone(),
+        "metadata": {},
         "wards": [],
         "players_state": [],
     });
 
-    let ward_spawn_packets = get_blocks_with_id(&file, config.ward_spawn_decrypt.netid as u16)
+    // one pass over the replay feeds every packet type we decode
+    let mut ward_spawn_blocks: Vec<BlockPayload> = Vec::new();
+    let mut path_blocks: Vec<BlockPayload> = Vec::new();
+    // one list per `packets` entry of the patch file
+    let mut packet_blocks: Vec<Vec<BlockPayload>> = vec![Vec::new(); config.packets.len()];
+    let mut keyframes: Vec<Keyframe> = Vec::new();
+
+    let mut demux = BlockDemux::default();
+    demux
+        .collect(
+            config.ward_spawn_decrypt.netid as u16,
+            &mut ward_spawn_blocks,
+        )
+        .collect(config.mov_decrypt.netid as u16, &mut path_blocks);
+    for (decrypt, blocks) in config.packets.iter().zip(packet_blocks.iter_mut()) {
+        demux.collect(decrypt.netid as u16, blocks);
+    }
+    // keyframes come out of the same pass instead of decompressing the replay twice
+    if config.keyframe_decrypt.is_some() {
+        demux.keyframes(&mut keyframes);
+    }
+    let recovery_report = demux.run(open_replay(replay_path)?, &options)?;
+
+    if options.recover {
+        if !recovery_report.is_clean() {
+            warn!(
+                "Recovered '{}', {} damaged range(s) skipped.",
+                replay_path,
+                recovery_report.lost.len()
+            );
+        }
+        game["lost_ranges"] = json!(recovery_report.lost);
+    }
+
+    let ward_spawn_packets = ward_spawn_blocks
         .par_iter()
-        .chunks(BATCH_SIZE)
-        .map(|payload_chunk| {
-            let mut emu = StubEmulator::new(config.clone());
-
-            emu.setup().unwrap();
-
-            payload_chunk
-                .into_iter()
-                .map(|(timestamp, payload)| {
-                    emu.setup_args(payload).unwrap();
-                    let packet = emu
-                        .call_decrypt_ward_spawn_packet(
-                            config.ward_spawn_decrypt.rva,
-                            config.ward_spawn_decrypt.end_rva,
-                            *timestamp,
-                        )
-                        .unwrap();
-                    emu.reset();
-                    packet
-                })
-                .collect::<Vec<WardSpawnPacket>>()
+        .filter_map(|(timestamp, payload)| {
+            let packet = with_emulator(config, |emu| {
+                emu.setup_args(payload)?;
+                emu.call_decrypt_ward_spawn_packet(
+                    config.ward_spawn_decrypt.rva,
+                    config.ward_spawn_decrypt.end_rva,
+                    *timestamp,
+                )
+            });
+
+            // one bad payload only costs its ward
+            packet
+                .map_err(|e| warn!("Skipping ward spawn packet at {:.3}s: {:#}", timestamp, e))
+                .ok()
         })
-        .flatten()
         .collect::<Vec<WardSpawnPacket>>();
 
+    if !config.packets.is_empty() {
+        game["packets"] = json!({});
+    }
+    for (decrypt, blocks) in config.packets.iter().zip(&packet_blocks) {
+        let records = blocks
+            .par_iter()
+            .filter_map(|(timestamp, payload)| {
+                let record = with_emulator(config, |emu| {
+                    emu.setup_args(payload)?;
+                    emu.call_decrypt_packet(decrypt, *timestamp)
+                });
+
+                record
+                    .map_err(|e| {
+                        warn!(
+                            "Skipping {} packet at {:.3}s: {:#}",
+                            decrypt.name, timestamp, e
+                        )
+                    })
+                    .ok()
+            })
+            .collect::<Vec<PacketRecord>>();
+
+        game["packets"][&decrypt.name] = json!(records);
+    }
+
+    let mut path_packets = path_blocks
+        .par_iter()
+        .filter_map(|(timestamp, payload)| {
+            with_emulator(config, |emu| {
+                emu.setup_args(payload)?;
+                emu.call_decrypt_pos_packet(
+                    config.mov_decrypt.rva,
+                    config.mov_decrypt.end_rva,
+                    *timestamp,
+                )
+            })
+            .map_err(|e| warn!("Skipping path packet at {:.3}s: {:#}", timestamp, e))
+            .ok()
+        })
+        .collect::<Vec<PathPacket>>();
+
+    path_packets.sort_by(|p1, p2| {
+        p1.timestamp
+            .partial_cmp(&p2.timestamp)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    // custom games and some older patches have no positions in the metadata
+    let mut metadata = metadata.clone();
+    infer_roles(
+        &mut metadata,
+        &path_packets,
+        config.player_id_start,
+        options.range,
+    );
+    game["metadata"] = json!(metadata);
+
     let mut placed_wards_map: HashMap<u32, WardSpawnPacket> = HashMap::new();
     let mut pos_id_map: HashMap<PosKey, u32> = HashMap::new();
     for packet in ward_spawn_packets {
@@ -123,12 +387,16 @@ fn get_replay_info(file: Vec<u8>, metadata: &Metadata, config: &Config) -> Value
         } else if packet.name.contains("Corpse") {
             if let Some((_, id)) = pos_id_map.remove_entry(&PosKey::new(packet.x, packet.y)) {
                 if let Some((_, p)) = placed_wards_map.remove_entry(&id) {
-                    let owner_player =
-                        metadata.get_player_from_id(p.owner_id, config.player_id_start);
+                    // the owner comes from the decrypted packet, a bad one costs the ward
+                    let Some(owner_player) =
+                        metadata.get_player_from_id(p.owner_id, config.player_id_start)
+                    else {
+                        continue;
+                    };
                     game["wards"].as_array_mut().unwrap().push(json!({
                         "name": p.name,
                         "team": owner_player.team, 
-                        "owner" : json!({ "name": owner_player.name, "team": owner_player.team, "role": owner_player.position}),
+                        "owner" : json!({ "name": owner_player.name, "puuid": owner_player.puuid, "team": owner_player.team, "role": owner_player.position}),
                         "timestamp": p.timestamp,
                         "duration": packet.timestamp - p.timestamp,
                         "pos" : [p.x, p.y],
@@ -138,36 +406,6 @@ fn get_replay_info(file: Vec<u8>, metadata: &Metadata, config: &Config) -> Value
         }
     }
 
-    let mut path_packets = get_blocks_with_id(&file, config.mov_decrypt.netid as u16)
-        .par_chunks(BATCH_SIZE)
-        .map(|payload_chunk| {
-            let mut emu = StubEmulator::new(config.clone());
-
-            emu.setup().unwrap();
-
-            payload_chunk
-                .iter()
-                .filter_map(|(timestamp, payload)| {
-                    emu.setup_args(payload).unwrap();
-                    let packet = emu.call_decrypt_pos_packet(
-                        config.mov_decrypt.rva,
-                        config.mov_decrypt.end_rva,
-                        *timestamp,
-                    );
-                    emu.reset();
-                    packet.ok()
-                })
-                .collect::<Vec<PathPacket>>()
-        })
-        .flatten()
-        .collect::<Vec<PathPacket>>();
-
-    path_packets.sort_by(|p1, p2| {
-        p1.timestamp
-            .partial_cmp(&p2.timestamp)
-            .unwrap_or(std::cmp::Ordering::Equal)
-    });
-
     let mut players_path_state: HashMap<u32, PathPacket> = HashMap::new();
     let mut timestamp = 0.0;
     for packet in path_packets {
@@ -183,12 +421,16 @@ fn get_replay_info(file: Vec<u8>, metadata: &Metadata, config: &Config) -> Value
 
             for (_, path) in players_path_state.iter() {
                 let (x, y) = path.get_pos(packet.timestamp);
-                let player = metadata.get_player_from_id(path.id, config.player_id_start);
+                let Some(player) = metadata.get_player_from_id(path.id, config.player_id_start)
+                else {
+                    continue;
+                };
                 state["players"].as_array_mut().unwrap().push(json!({
                     "role": player.position,
                     "team": player.team,
                     "name": player.name,
-                    "champ": player.skin,
+                    "puuid": player.puuid,
+                    "champ": player.champion,
                     "pos": [x, y],
                 }));
             }
@@ -198,43 +440,171 @@ fn get_replay_info(file: Vec<u8>, metadata: &Metadata, config: &Config) -> Value
         }
     }
 
-    game
+    if let Some(keyframe_decrypt) = &config.keyframe_decrypt {
+        game["keyframes"] =
+            get_keyframes_info(keyframes, &metadata, config, keyframe_decrypt, options)?;
+    }
+
+    Ok(game)
 }
 
-fn parse_batch(replay_folder: String, output_folder: String, patch_version: String) {
+fn get_keyframes_info(
+    mut keyframes: Vec<Keyframe>,
+    metadata: &Metadata,
+    config: &Config,
+    keyframe_decrypt: &KeyframeDecrypt,
+    options: ParseOptions,
+) -> Result<Value> {
+    if let Some((start, end)) = options.range {
+        keyframes.retain(|keyframe| keyframe.timestamp >= start && keyframe.timestamp <= end);
+    }
+
+    let keyframes_states = keyframes
+        .par_iter()
+        .map(|keyframe| {
+            let states = keyframe
+                .blocks_with_id(keyframe_decrypt.netid as u16)
+                .filter_map(|block| {
+                    with_emulator(config, |emu| {
+                        emu.setup_args(&block.payload)?;
+                        emu.call_decrypt_keyframe_state(keyframe_decrypt, block.timestamp)
+                    })
+                    .map_err(|e| {
+                        warn!(
+                            "Skipping keyframe state at {:.3}s: {:#}",
+                            block.timestamp, e
+                        )
+                    })
+                    .ok()
+                })
+                .collect::<Vec<EntityState>>();
+
+            (keyframe, states)
+        })
+        .collect::<Vec<_>>();
+
+    let mut keyframes_info = json!([]);
+    for (keyframe, states) in keyframes_states {
+        let mut entities = json!([]);
+        for state in states {
+            let mut entity = json!({
+                "id": state.id,
+                "pos": [state.x, state.y],
+                "health": state.health,
+                "max_health": state.max_health,
+                "level": state.level,
+                "items": state.items,
+            });
+
+            if let Some(player) = metadata.get_player_from_id(state.id, config.player_id_start) {
+                entity["role"] = json!(player.position);
+                entity["team"] = json!(player.team);
+                entity["name"] = json!(player.name);
+                entity["puuid"] = json!(player.puuid);
+                entity["champ"] = json!(player.champion);
+            }
+
+            entities.as_array_mut().unwrap().push(entity);
+        }
+
+        keyframes_info.as_array_mut().unwrap().push(json!({
+            "id": keyframe.id,
+            "timestamp": keyframe.timestamp,
+            "entities": entities,
+        }));
+    }
+
+    Ok(keyframes_info)
+}
+
+fn read_replay(
+    replay_path: &str,
+    patch_version: &GameVersion,
+    config: &Config,
+    options: ParseOptions,
+    exclude_remakes: bool,
+) -> Result<Option<Value>> {
+    let metadata = Metadata::from_path(replay_path)?;
+
+    if exclude_remakes && metadata.outcome == GameOutcome::Remake {
+        return Ok(None);
+    }
+
+    // offsets of another patch would decrypt garbage
+    if !metadata.version.same_patch(patch_version) {
+        bail!(
+            "replay is from game version {}, the patch config is for {}",
+            metadata.version,
+            patch_version.patch_name()
+        );
+    }
+
+    get_replay_info(replay_path, &metadata, config, options).map(Some)
+}
+
+fn parse_batch(
+    replay_folder: String,
+    output_folder: String,
+    patch_version: GameVersion,
+    options: ParseOptions,
+    exclude_remakes: bool,
+    limits: EmulationLimits,
+    lazy_map: bool,
+) {
     let start = std::time::Instant::now();
 
-    let config = Config::parse(&get_appropiate_patch(patch_version));
+    let config = match load_config(&patch_version, limits, lazy_map) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{:#}", e);
+            return;
+        }
+    };
 
-    let files: Vec<_> = std::fs::read_dir(replay_folder)
-        .unwrap()
-        .map(|f| f.ok())
-        .collect();
+    let files: Vec<PathBuf> = match std::fs::read_dir(&replay_folder) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .collect(),
+        Err(e) => {
+            error!("Failed to read folder '{}': {}", replay_folder, e);
+            return;
+        }
+    };
 
     let file_count = files.len();
     let i = Arc::new(Mutex::new(1));
 
     files.into_par_iter().for_each(|file| {
-        let replay_path = file.as_ref().unwrap().path().display().to_string();
+        let replay_path = file.display().to_string();
         let name = file
-            .as_ref()
-            .unwrap()
-            .path()
             .file_name()
-            .to_owned()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string();
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
 
-        let file = read_file(replay_path.clone());
-        let metadata = Metadata::parse(&file);
-
-        let game = get_replay_info(file, &metadata, &config);
+        // a single broken replay must not take the whole batch down
+        let game = match read_replay(
+            &replay_path,
+            &patch_version,
+            &config,
+            options,
+            exclude_remakes,
+        ) {
+            Ok(Some(game)) => game,
+            Ok(None) => {
+                info!("Skipping remake '{}'.", replay_path);
+                return;
+            }
+            Err(e) => {
+                error!("Skipping file '{}': {:#}", replay_path, e);
+                return;
+            }
+        };
 
         let json_path = PathBuf::from(output_folder.clone()).join(name + ".json");
-        let mut json = File::create(json_path).unwrap();
-        json.write_all(game.to_string().as_bytes()).unwrap();
+        if let Err(e) = write_json(&json_path, &game) {
+            error!("Failed to write '{}': {}", json_path.display(), e);
+            return;
+        }
 
         let mut i = i.lock().unwrap();
         info!(
@@ -244,55 +614,340 @@ fn parse_batch(replay_folder: String, output_folder: String, patch_version: Stri
         *i += 1;
     });
 
+    log_unmapped_report(&config);
+
     let end = start.elapsed().as_secs_f32();
     info!("Total execution time: {:.3}", end);
 }
 
-fn get_appropiate_patch(version: String) -> PathBuf {
-    let mut patch_file_name = version.replace(".", "-");
-    patch_file_name.pop();
-    let patch_file = format!("./patch/{}.patch", patch_file_name);
-
-    let patch_path = Path::new(&patch_file);
+fn get_appropiate_patch(version: &GameVersion) -> Result<PathBuf> {
+    // patch files are named after major.minor, e.g. 15.4.658.1234 -> 15-4.patch
+    match find_patch(Path::new(PATCH_DIR), version) {
+        PatchLookup::Exact(patch_path) => {
+            info!("Loaded patch config from: {}", patch_path.display());
+            Ok(patch_path)
+        }
+        missing => bail!("{}", missing),
+    }
+}
 
-    if !patch_path.exists() {
-        panic!("Patch file not found for version: {}", version);
+fn load_config(version: &GameVersion, limits: EmulationLimits, lazy_map: bool) -> Result<Config> {
+    let mut config = Config::parse(&get_appropiate_patch(version)?);
+    config.limits = limits;
+    if lazy_map {
+        config.unmapped_report = Some(Default::default());
     }
 
-    info!("Loaded patch config from: {}.patch", patch_file_name);
+    Ok(config)
+}
+
+// lists the unmapped reads answered while decoding, when --lazy-map was given
+fn log_unmapped_report(config: &Config) {
+    let Some(report) = &config.unmapped_report else {
+        return;
+    };
 
-    patch_path.to_path_buf()
+    let report = report.lock().unwrap();
+    if report.is_empty() {
+        info!("No unmapped reads were needed.");
+    } else {
+        warn!("{}", report);
+    }
 }
 
-fn parse_file(replay_file: String, output_file: String, debug: bool) {
+fn parse_file(
+    replay_file: String,
+    output_file: String,
+    debug: bool,
+    options: ParseOptions,
+    limits: EmulationLimits,
+    lazy_map: bool,
+) {
     let start = std::time::Instant::now();
 
     if debug {
         info!("Replay file: {}", replay_file);
     }
 
-    let file = read_file(replay_file.clone());
-    let metadata = Metadata::parse(&file);
-    let config = Config::parse(&get_appropiate_patch(metadata.version.clone()));
+    let metadata = match Metadata::from_path(&replay_file) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            error!("Failed to read '{}': {:#}", replay_file, e);
+            return;
+        }
+    };
+    let config = match load_config(&metadata.version, limits, lazy_map) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{:#}", e);
+            return;
+        }
+    };
 
     if debug {
         info!("Parsed config successfully.");
     }
 
-    let game = get_replay_info(file, &metadata, &config);
+    let game = match get_replay_info(&replay_file, &metadata, &config, options) {
+        Ok(game) => game,
+        Err(e) => {
+            error!("Failed to parse '{}': {:#}", replay_file, e);
+            return;
+        }
+    };
 
     if debug {
         info!("Game parsed successfully.");
     }
 
-    let json_path = PathBuf::from(output_file.clone());
-    let mut json = File::create(json_path).unwrap();
-    json.write_all(game.to_string().as_bytes()).unwrap();
+    if let Err(e) = write_json(Path::new(&output_file), &game) {
+        error!("Failed to write '{}': {}", output_file, e);
+        return;
+    }
 
     if debug {
         info!("Writed to json file.");
     }
 
+    log_unmapped_report(&config);
+
+    let end = start.elapsed().as_secs_f32();
+    info!("Output: {}, Total execution time: {:.3}", output_file, end);
+}
+
+// accepts both decimal and 0x prefixed hex, entity ids are easier to read in hex
+fn parse_number(s: &str) -> Result<u32, std::num::ParseIntError> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+fn dump_file(
+    replay_file: String,
+    output_file: String,
+    format: DumpFormat,
+    filter: DumpFilter,
+    options: ParseOptions,
+) {
+    let start = std::time::Instant::now();
+
+    let (replay, output) = match open_replay(&replay_file).and_then(|replay| {
+        File::create(&output_file).map(|output| (replay, BufWriter::new(output)))
+    }) {
+        Ok(files) => files,
+        Err(e) => {
+            error!(
+                "Failed to dump '{}' to '{}': {}",
+                replay_file, output_file, e
+            );
+            return;
+        }
+    };
+    let count = match dump_blocks(replay, output, &options, &filter, format) {
+        Ok(count) => count,
+        Err(e) => {
+            error!("Failed to dump '{}': {:#}", replay_file, e);
+            return;
+        }
+    };
+
+    let end = start.elapsed().as_secs_f32();
+    info!(
+        "Dumped {} blocks to {}, Total execution time: {:.3}",
+        count, output_file, end
+    );
+}
+
+// decrypts a single block with tracing on, for working out the offsets of a patch file
+fn trace_file(
+    replay_file: String,
+    output_file: String,
+    packet_id: u16,
+    index: usize,
+    limits: EmulationLimits,
+    lazy_map: bool,
+) -> Result<()> {
+    let metadata = Metadata::from_path(&replay_file)?;
+    let config = load_config(&metadata.version, limits, lazy_map)?;
+
+    let keyframe_decrypt = config
+        .keyframe_decrypt
+        .as_ref()
+        .filter(|decrypt| decrypt.netid as u16 == packet_id);
+
+    // keyframe entity states are only found in keyframes, everything else in the chunks
+    let block = match keyframe_decrypt {
+        Some(_) => get_keyframes(open_replay(&replay_file)?, false)?
+            .iter()
+            .flat_map(|keyframe| keyframe.blocks_with_id(packet_id))
+            .nth(index)
+            .map(|block| (block.timestamp, block.payload.to_vec())),
+        None => {
+            let mut blocks: Vec<BlockPayload> = Vec::new();
+            let mut demux = BlockDemux::default();
+            demux.collect(packet_id, &mut blocks);
+            demux.run(open_replay(&replay_file)?, &ParseOptions::default())?;
+            blocks.into_iter().nth(index)
+        }
+    };
+    let Some((timestamp, payload)) = block else {
+        bail!(
+            "no block #{} with packet id {} in '{}'",
+            index,
+            packet_id,
+            replay_file
+        );
+    };
+
+    // hooks can't be removed from a traced emulator, so it doesn't come from the pool
+    let mut emu = StubEmulator::new(config.clone());
+    emu.setup()?;
+    emu.setup_args(&payload)?;
+    let trace = emu.trace()?;
+
+    let decoded = if packet_id == config.ward_spawn_decrypt.netid as u16 {
+        emu.call_decrypt_ward_spawn_packet(
+            config.ward_spawn_decrypt.rva,
+            config.ward_spawn_decrypt.end_rva,
+            timestamp,
+        )
+        .map(|packet| format!("{:?}", packet))
+    } else if packet_id == config.mov_decrypt.netid as u16 {
+        emu.call_decrypt_pos_packet(
+            config.mov_decrypt.rva,
+            config.mov_decrypt.end_rva,
+            timestamp,
+        )
+        .map(|packet| format!("{:?}", packet))
+    } else if let Some(keyframe_decrypt) = keyframe_decrypt {
+        emu.call_decrypt_keyframe_state(keyframe_decrypt, timestamp)
+            .map(|state| format!("{:?}", state))
+    } else if let Some(decrypt) = config
+        .packets
+        .iter()
+        .find(|decrypt| decrypt.netid as u16 == packet_id)
+    {
+        emu.call_decrypt_packet(decrypt, timestamp)
+            .map(|record| serde_json::to_string(&record).unwrap())
+    } else {
+        bail!(
+            "the patch file has no decrypt function for packet id {}",
+            packet_id
+        );
+    };
+
+    match decoded {
+        Ok(decoded) => info!("Decoded block at {:.3}s: {}", timestamp, decoded),
+        Err(e) => warn!("Decrypt of block at {:.3}s failed: {:#}", timestamp, e),
+    }
+
+    // the trace is written either way, a failed decrypt is when it's needed most
+    let events = trace.lock().unwrap();
+    write_trace(&events, BufWriter::new(File::create(&output_file)?))?;
+    info!("Wrote {} trace events to {}", events.len(), output_file);
+    log_unmapped_report(&config);
+
+    Ok(())
+}
+
+fn info_files(replay_paths: Vec<String>, json: bool) {
+    let start = std::time::Instant::now();
+
+    // folders are expanded to the replays they hold
+    let files: Vec<PathBuf> = replay_paths
+        .iter()
+        .map(PathBuf::from)
+        .flat_map(|path| {
+            if !path.is_dir() {
+                return vec![path];
+            }
+
+            let entries = match std::fs::read_dir(&path) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    error!("Skipping folder '{}': {}", path.display(), e);
+                    return Vec::new();
+                }
+            };
+            let mut replays: Vec<PathBuf> = entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "rofl"))
+                .collect();
+            replays.sort();
+            replays
+        })
+        .collect();
+
+    // only the header and the metadata at the end of each file are read
+    for file in &files {
+        match Metadata::from_path(file) {
+            Ok(metadata) if json => println!(
+                "{}",
+                json!({ "file": file.display().to_string(), "metadata": metadata })
+            ),
+            Ok(metadata) => print!("{}\n{}", file.display(), metadata),
+            Err(e) => error!("Skipping file '{}': {:#}", file.display(), e),
+        }
+    }
+
+    if !json {
+        let end = start.elapsed().as_secs_f32();
+        info!(
+            "Read {} replay(s), Total execution time: {:.3}",
+            files.len(),
+            end
+        );
+    }
+}
+
+fn inspect_file(replay_file: String, json: bool) {
+    let stats = match open_replay(&replay_file)
+        .map_err(ParseError::from)
+        .and_then(ReplayStats::collect)
+    {
+        Ok(stats) => stats,
+        Err(e) => {
+            error!("Failed to inspect '{}': {:#}", replay_file, e);
+            return;
+        }
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&stats).unwrap());
+    } else {
+        print!("{}", stats);
+    }
+}
+
+fn rewrite_file(replay_file: String, output_file: String, options: RewriteOptions) {
+    let start = std::time::Instant::now();
+
+    let (replay, output) = match open_replay(&replay_file).and_then(|replay| {
+        File::create(&output_file).map(|output| (replay, BufWriter::new(output)))
+    }) {
+        Ok(files) => files,
+        Err(e) => {
+            error!(
+                "Failed to rewrite '{}' to '{}': {}",
+                replay_file, output_file, e
+            );
+            return;
+        }
+    };
+    let summary = match rewrite(replay, output, &options) {
+        Ok(summary) => summary,
+        Err(e) => {
+            error!("Failed to rewrite '{}': {:#}", replay_file, e);
+            return;
+        }
+    };
+
+    info!(
+        "Kept {} chunks, {} keyframes and {} blocks, dropped {} blocks.",
+        summary.chunks, summary.keyframes, summary.blocks, summary.dropped_blocks
+    );
+
     let end = start.elapsed().as_secs_f32();
     info!("Output: {}, Total execution time: {:.3}", output_file, end);
 }
@@ -322,18 +977,110 @@ fn main() {
             replay_file,
             output_file,
             debug,
+            start,
+            end,
+            recover,
+            parallel,
+            emulation,
         } => {
+            let options = ParseOptions {
+                range: time_range(start, end),
+                recover,
+                parallel,
+            };
+            let (limits, lazy_map) = emulation.into();
             if debug {
-                parse_file(replay_file, output_file, true);
+                parse_file(replay_file, output_file, true, options, limits, lazy_map);
             } else {
-                parse_file(replay_file, output_file, false);
+                parse_file(replay_file, output_file, false, options, limits, lazy_map);
             }
         }
         Parsing::Folder {
             replay_folder,
             output_folder,
             patch_version,
-        } => parse_batch(replay_folder, output_folder, patch_version),
-        _ => unimplemented!("Batch parsing not implemented yet"),
+            start,
+            end,
+            recover,
+            exclude_remakes,
+            emulation,
+        } => {
+            let (limits, lazy_map) = emulation.into();
+            parse_batch(
+                replay_folder,
+                output_folder,
+                patch_version,
+                ParseOptions {
+                    range: time_range(start, end),
+                    recover,
+                    ..Default::default()
+                },
+                exclude_remakes,
+                limits,
+                lazy_map,
+            )
+        }
+        Parsing::Dump {
+            replay_file,
+            output_file,
+            format,
+            packet_ids,
+            params,
+            start,
+            end,
+        } => dump_file(
+            replay_file,
+            output_file,
+            format,
+            DumpFilter { packet_ids, params },
+            ParseOptions {
+                range: time_range(start, end),
+                ..Default::default()
+            },
+        ),
+        Parsing::Trace {
+            replay_file,
+            output_file,
+            packet,
+            index,
+            emulation,
+        } => {
+            let (limits, lazy_map) = emulation.into();
+            if let Err(e) = trace_file(replay_file, output_file, packet, index, limits, lazy_map) {
+                error!("{:#}", e);
+            }
+        }
+        Parsing::Info { replay_paths, json } => info_files(replay_paths, json),
+        Parsing::Inspect { replay_file, json } => inspect_file(replay_file, json),
+        Parsing::Rewrite {
+            replay_file,
+            output_file,
+            start,
+            end,
+            drop_packet,
+            anonymize,
+            salt,
+        } => {
+            let salt = salt
+                .or_else(|| env::var(ANONYMIZE_SALT_VAR).ok())
+                .filter(|salt| !salt.is_empty());
+            if anonymize && salt.is_none() {
+                error!(
+                    "--anonymize needs a secret salt, pass --salt or set {}",
+                    ANONYMIZE_SALT_VAR
+                );
+                return;
+            }
+
+            rewrite_file(
+                replay_file,
+                output_file,
+                RewriteOptions {
+                    range: time_range(start, end),
+                    drop_packet_ids: drop_packet,
+                    anonymize: salt.filter(|_| anonymize).map(String::into_bytes),
+                },
+            )
+        }
     }
 }
@@ -1,31 +1,46 @@
+use std::borrow::Cow;
 use std::fmt as display;
 
+use crate::parser::error::{ParseError, ParseResult};
 use crate::parser::util::*;
 
 #[derive(Debug)]
-pub struct Block {
+pub struct Block<'a> {
     // HEADER
     pub length: u32,
     pub timestamp: f32,
     pub packet_id: u16,
     pub param: u32,
-    // PAYLOAD
-    pub payload: Vec<u8>,
+    // PAYLOAD, borrowed from the decompressed chunk until into_owned
+    pub payload: Cow<'a, [u8]>,
 }
 
-impl Default for Block {
+impl Default for Block<'_> {
     fn default() -> Self {
         Block {
             length: 0,
             timestamp: 0.0,
             packet_id: 0,
             param: 0,
-            payload: Vec::new(),
+            payload: Cow::Borrowed(&[]),
         }
     }
 }
 
-impl display::Display for Block {
+impl Block<'_> {
+    // detaches the block from its chunk buffer
+    pub fn into_owned(self) -> Block<'static> {
+        Block {
+            length: self.length,
+            timestamp: self.timestamp,
+            packet_id: self.packet_id,
+            param: self.param,
+            payload: Cow::Owned(self.payload.into_owned()),
+        }
+    }
+}
+
+impl display::Display for Block<'_> {
     fn fmt(&self, f: &mut display::Formatter<'_>) -> display::Result {
         write!(
             f,
@@ -35,49 +50,128 @@ impl display::Display for Block {
     }
 }
 
-pub struct BlockParser {
-    chunk: Box<dyn Iterator<Item = u8>>,
+pub struct BlockParser<'a> {
+    chunk: std::slice::Iter<'a, u8>,
+    chunk_len: usize,
 
     acc_time: f32, // accumulated time in seconds
 
     previous_block_packet_id: u16,
     previous_block_param: u32,
+
+    // in recovery mode the first damaged block ends the chunk instead of failing
+    recover: bool,
+    damage: Option<ParseError>,
 }
 
-impl BlockParser {
-    pub fn new(chunk: Vec<u8>) -> BlockParser {
+impl<'a> BlockParser<'a> {
+    pub fn new(chunk: &'a [u8]) -> BlockParser<'a> {
         BlockParser {
-            chunk: Box::new(chunk.into_iter()),
+            chunk_len: chunk.len(),
+            chunk: chunk.iter(),
             acc_time: 0.0,
             previous_block_packet_id: 0,
             previous_block_param: 0,
+            recover: false,
+            damage: None,
+        }
+    }
+
+    pub fn recover(&mut self) {
+        self.recover = true;
+    }
+
+    // the error that stopped a recovering parser, if any
+    pub fn take_damage(&mut self) -> Option<ParseError> {
+        self.damage.take()
+    }
+
+    // offset of the next byte to be read, relative to the start of the chunk
+    pub fn offset(&self) -> u64 {
+        (self.chunk_len - self.chunk.len()) as u64
+    }
+
+    pub fn next_block(&mut self) -> ParseResult<Option<Block<'a>>> {
+        if !self.recover {
+            return self.read_block();
+        }
+
+        if self.damage.is_some() {
+            return Ok(None);
+        }
+
+        match self.read_block() {
+            Err(e) => {
+                // nothing after a damaged block can be trusted, its length may be garbage
+                self.damage = Some(e);
+                Ok(None)
+            }
+            result => result,
         }
     }
 
-    pub fn next_block(&mut self) -> Option<Block> {
+    fn read_block(&mut self) -> ParseResult<Option<Block<'a>>> {
+        let block_offset = self.offset();
+        let Some(mut block) = self.next_block_header()? else {
+            return Ok(None);
+        };
+
+        // corrupted data shows up as timestamps no game can have
+        if self.recover && !(block.timestamp.is_finite() && block.timestamp >= 0.0) {
+            return Err(ParseError::invalid("block timestamp", block.timestamp).at(block_offset));
+        }
+
+        let rest = self.chunk.as_slice();
+        if rest.len() < block.length as usize {
+            return Err(ParseError::eof("block payload").at(block_offset));
+        }
+        let (payload, rest) = rest.split_at(block.length as usize);
+        block.payload = Cow::Borrowed(payload);
+        self.chunk = rest.iter();
+
+        Ok(Some(block))
+    }
+
+    // decodes the block header and leaves the parser right before its payload
+    pub fn next_block_header(&mut self) -> ParseResult<Option<Block<'a>>> {
         let mut block: Block = Block::default();
 
-        let marker = self.chunk.next()?;
+        let block_offset = self.offset();
+        let Some(&marker) = self.chunk.next() else {
+            return Ok(None);
+        };
+
+        let eof = |expected| ParseError::eof(expected).at(block_offset);
 
         // TIMESTAMP
         if marker & 0x80 != 0 {
             // time relative to previous block
-            let timestamp = self.chunk.next().unwrap();
+            let timestamp = self
+                .chunk
+                .next()
+                .copied()
+                .ok_or_else(|| eof("relative timestamp"))?;
             self.acc_time += timestamp as f32 * 0.001;
         } else {
             // absolute time
-            self.acc_time = parse_f32(&mut self.chunk).expect("failed to parse timestamp");
+            self.acc_time =
+                parse_f32(&mut self.chunk.by_ref().copied()).map_err(|_| eof("timestamp"))?;
         }
         block.timestamp = self.acc_time;
 
         // BLOCK LENGTH
         if marker & 0x10 != 0 {
             // u8 length
-            let block_len = self.chunk.next().unwrap();
+            let block_len = self
+                .chunk
+                .next()
+                .copied()
+                .ok_or_else(|| eof("u8 block length"))?;
             block.length = block_len as u32;
         } else {
             // u32 length
-            block.length = parse_u32(&mut self.chunk).unwrap();
+            block.length = parse_u32(&mut self.chunk.by_ref().copied())
+                .map_err(|_| eof("u32 block length"))?;
         }
 
         // PACKET ID
@@ -86,24 +180,179 @@ impl BlockParser {
             block.packet_id = self.previous_block_packet_id
         } else {
             // u16 packet_id
-            block.packet_id = parse_u16(&mut self.chunk).unwrap();
+            block.packet_id =
+                parse_u16(&mut self.chunk.by_ref().copied()).map_err(|_| eof("packet id"))?;
         }
 
         // BLOCK PARAM
         if marker & 0x20 != 0 {
             // relative to previous block
-            let block_param = self.chunk.next().unwrap();
+            let block_param = self
+                .chunk
+                .next()
+                .copied()
+                .ok_or_else(|| eof("relative block param"))?;
             block.param = block_param as u32 + self.previous_block_param;
         } else {
             // u32 block_param
-            block.param = parse_u32(&mut self.chunk).unwrap();
+            block.param =
+                parse_u32(&mut self.chunk.by_ref().copied()).map_err(|_| eof("block param"))?;
+        }
+
+        self.previous_block_packet_id = block.packet_id;
+        self.previous_block_param = block.param;
+
+        Ok(Some(block))
+    }
+}
+
+// inverse of BlockParser, uses the most compact marker that decodes back to the same block
+#[derive(Default)]
+pub struct BlockWriter {
+    buffer: Vec<u8>,
+
+    acc_time: f32, // accumulated time in seconds, as BlockParser computes it
+
+    previous_block_packet_id: u16,
+    previous_block_param: u32,
+}
+
+impl BlockWriter {
+    pub fn write_block(&mut self, block: &Block) {
+        let mut marker: u8 = 0;
+        let mut header: Vec<u8> = Vec::with_capacity(0xF);
+
+        // TIMESTAMP
+        let delta_ms = ((block.timestamp - self.acc_time) * 1000.0).round();
+        let relative_time = (0.0..=255.0).contains(&delta_ms)
+            && self.acc_time + (delta_ms as u8) as f32 * 0.001 == block.timestamp;
+        if relative_time {
+            // only when the parser lands on the exact same f32
+            marker |= 0x80;
+            header.push(delta_ms as u8);
+        } else {
+            header.extend_from_slice(&block.timestamp.to_le_bytes());
+        }
+        self.acc_time = block.timestamp;
+
+        // BLOCK LENGTH
+        let block_len = block.payload.len();
+        if block_len <= u8::MAX as usize {
+            marker |= 0x10;
+            header.push(block_len as u8);
+        } else {
+            header.extend_from_slice(&(block_len as u32).to_le_bytes());
+        }
+
+        // PACKET ID
+        if block.packet_id == self.previous_block_packet_id {
+            marker |= 0x40;
+        } else {
+            header.extend_from_slice(&block.packet_id.to_le_bytes());
         }
 
-        block.payload = self.chunk.by_ref().take(block.length as usize).collect();
+        // BLOCK PARAM
+        match block.param.checked_sub(self.previous_block_param) {
+            Some(delta) if delta <= u8::MAX as u32 => {
+                marker |= 0x20;
+                header.push(delta as u8);
+            }
+            _ => header.extend_from_slice(&block.param.to_le_bytes()),
+        }
 
         self.previous_block_packet_id = block.packet_id;
         self.previous_block_param = block.param;
 
-        Some(block)
+        self.buffer.push(marker);
+        self.buffer.extend_from_slice(&header);
+        self.buffer.extend_from_slice(&block.payload);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buffer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(timestamp: f32, packet_id: u16, param: u32, payload: Vec<u8>) -> Block<'static> {
+        Block {
+            length: payload.len() as u32,
+            timestamp,
+            packet_id,
+            param,
+            payload: Cow::Owned(payload),
+        }
+    }
+
+    fn round_trip(blocks: &[Block]) -> Vec<u8> {
+        let mut writer = BlockWriter::default();
+        for block in blocks {
+            writer.write_block(block);
+        }
+        let chunk = writer.finish();
+
+        let mut parser = BlockParser::new(&chunk);
+        for expected in blocks {
+            let block = parser.next_block().unwrap().unwrap();
+            assert_eq!(block.timestamp, expected.timestamp);
+            assert_eq!(block.length, expected.length);
+            assert_eq!(block.packet_id, expected.packet_id);
+            assert_eq!(block.param, expected.param);
+            assert_eq!(block.payload, expected.payload);
+        }
+        assert!(parser.next_block().unwrap().is_none());
+
+        chunk
+    }
+
+    #[test]
+    fn writer_round_trips_every_marker() {
+        let t1 = 1.5;
+        let t2 = t1 + 100.0 * 0.001;
+        let t3 = t2 + 20.0 * 0.001;
+        let blocks = [
+            // absolute time, u8 length, new packet id, absolute param
+            block(t1, 0x110, 0x40000005, vec![1, 2, 3]),
+            // relative time, u32 length, same packet id, relative param
+            block(t2, 0x110, 0x40000008, vec![0xAB; 300]),
+            // relative time, u8 length, new packet id, param below the previous one
+            block(t3, 0x2A, 7, vec![9; 5]),
+        ];
+
+        let chunk = round_trip(&blocks);
+
+        let second = 1 + 4 + 1 + 2 + 4 + 3;
+        let third = second + 1 + 1 + 4 + 1 + 300;
+        assert_eq!(chunk[0], 0x10);
+        assert_eq!(chunk[second], 0x80 | 0x40 | 0x20);
+        assert_eq!(chunk[third], 0x80 | 0x10);
+    }
+
+    #[test]
+    fn writer_falls_back_to_absolute_time() {
+        // 0.3s is past a u8 of milliseconds, 0.0001s doesn't land on the same f32
+        round_trip(&[
+            block(10.0, 1, 1, vec![]),
+            block(10.3, 1, 1, vec![]),
+            block(10.3001, 1, 1, vec![]),
+        ]);
+    }
+
+    #[test]
+    fn parser_reports_truncated_blocks() {
+        let mut writer = BlockWriter::default();
+        writer.write_block(&block(1.0, 1, 1, vec![0; 16]));
+        let chunk = writer.finish();
+
+        let mut parser = BlockParser::new(&chunk[..chunk.len() - 1]);
+        assert!(parser.next_block().is_err());
+
+        let mut parser = BlockParser::new(&chunk[..chunk.len() - 1]);
+        parser.recover();
+        assert!(parser.next_block().unwrap().is_none());
+        assert!(parser.take_damage().is_some());
     }
 }
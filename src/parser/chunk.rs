@@ -1,8 +1,29 @@
-use std::io::Read;
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, SeekFrom};
 
 use zstd::stream::read::Decoder;
 
-use crate::parser::util::*;
+use crate::parser::block::BlockParser;
+use crate::parser::error::{ParseError, ParseResult};
+use crate::parser::header::RoflHeader;
+use crate::parser::index::ReplayIndex;
+use crate::parser::keyframe::KEYFRAME_CHUNK_TYPE;
+use crate::parser::legacy::{self, LegacyChunkTable, LegacyPayloadHeader};
+use crate::parser::recovery::RecoveryReport;
+
+const CHUNK_HEADER_SIZE: usize = 0x11;
+
+// enough to decode the header of the first block of a chunk
+const BLOCK_HEADER_MAX_SIZE: usize = 0x10;
+
+// every chunk payload is a single zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+// bytes needed to recognize a chunk header: the header and the magic of its frame
+const CHUNK_PROBE_SIZE: usize = CHUNK_HEADER_SIZE + ZSTD_MAGIC.len();
+
+// read at once while scanning damaged bytes for the next chunk header
+const SCAN_WINDOW_SIZE: usize = 0x10000;
 
 #[derive(Debug)]
 pub struct Chunk {
@@ -13,91 +34,570 @@ pub struct Chunk {
     pub compressed_len: u32,
 
     pub payload: Option<Vec<u8>>,
+
+    // recovery mode only: damaged bytes skipped right before this chunk
+    pub skipped: Option<ParseError>,
+    // recovery mode only: the payload stops where this error happened
+    pub damage: Option<ParseError>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChunkHeader {
+    pub id: u32,
+    pub type_: u8,
+    pub id_2: u32,
+    pub uncompressed_len: u32,
+    pub compressed_len: u32,
+
+    // absolute offset of the chunk payload in the replay
+    pub payload_offset: u64,
 }
 
-pub struct ChunkParser {
-    replay_content: Box<dyn Iterator<Item = u8>>,
+// a chunk as stored in the replay, owns everything needed to decode it on another thread
+pub struct RawChunk {
+    pub header: ChunkHeader,
 
-    replay_len: u32,
-    cursor: usize,
+    data: Option<Vec<u8>>,
+    // v1 chunks are encrypted
+    chunk_key: Option<Vec<u8>>,
+
+    recover: bool,
+    skipped: Option<ParseError>,
+    damage: Option<ParseError>,
 }
 
-impl ChunkParser {
-    pub fn new(mut buffer: Vec<u8>) -> Self {
-        let (_, last_4_bytes) = buffer.split_at(buffer.len() - 4);
-        let metadata_len = u32::from_le_bytes(last_4_bytes.try_into().unwrap());
+impl RawChunk {
+    pub fn decode(self) -> ParseResult<Chunk> {
+        let RawChunk {
+            header,
+            data,
+            chunk_key,
+            recover,
+            skipped,
+            mut damage,
+        } = self;
 
-        buffer.truncate(buffer.len() - metadata_len as usize - 4); // remove metadata
-        buffer.truncate(buffer.len() - 0x100); // remove signature
-                                               //buffer.drain(0..0x1D); // remove replay header
-        Self::skip_rofl_header_size(&mut buffer);
+        let payload = match (data, chunk_key) {
+            (None, _) => None,
+            (Some(data), Some(chunk_key)) => {
+                match legacy::decode_payload(&chunk_key, &header, data) {
+                    Ok(payload) => Some(payload),
+                    Err(e) if recover => {
+                        damage = Some(e);
+                        None
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            (Some(data), None) => {
+                let mut uncompressed_payload = Vec::with_capacity(header.uncompressed_len as usize);
+                let decompressed = Decoder::new(data.as_slice())
+                    .and_then(|mut decoder| decoder.read_to_end(&mut uncompressed_payload));
+                if let Err(e) = decompressed {
+                    let error = ParseError::decompress(e)
+                        .at(header.payload_offset)
+                        .in_chunk(header.id);
+                    if !recover {
+                        return Err(error);
+                    }
 
-        let replay_len = buffer.len() as u32;
+                    // read_to_end keeps everything decoded before the damaged frame block
+                    damage = Some(error);
+                }
 
-        ChunkParser {
-            replay_content: Box::new(buffer.into_iter()),
-            replay_len,
-            cursor: 0,
-        }
+                Some(uncompressed_payload)
+            }
+        };
+
+        Ok(Chunk {
+            id: header.id,
+            type_: header.type_,
+            id_2: header.id_2,
+            uncompressed_len: payload
+                .as_ref()
+                .map_or(header.uncompressed_len, |p| p.len() as u32),
+            compressed_len: header.compressed_len,
+            payload,
+            skipped,
+            damage,
+        })
     }
+}
 
-    fn skip_rofl_header_size(buffer: &mut Vec<u8>) {
-        // FIXME: very bad
-        buffer.drain(0..0x10);
-        if buffer[0xC] == 1 {
-            buffer.drain(0..0xC);
-        } else {
-            buffer.drain(0..0xD);
+pub struct ChunkParser<R: Read + Seek> {
+    reader: R,
+
+    payload_start: u64,
+    payload_end: u64,
+    cursor: u64,
+
+    // ROFL v1 replays list their chunks upfront instead of inlining the headers
+    legacy_chunks: Option<LegacyChunkTable>,
+
+    // set in recovery mode, damaged chunks are skipped and reported here
+    recovery: Option<RecoveryReport>,
+    // damage found while scanning headers, handed to the first chunk read after it so the
+    // report stays in block order even when headers are scanned ahead
+    skipped: BTreeMap<u64, ParseError>,
+}
+
+impl<R: Read + Seek> ChunkParser<R> {
+    pub fn new(mut reader: R) -> ParseResult<Self> {
+        let header = RoflHeader::read(&mut reader)?;
+
+        let legacy_chunks = match &header.legacy {
+            Some(layout) => {
+                let payload_header = LegacyPayloadHeader::read(&mut reader, layout)?;
+                Some(LegacyChunkTable::read(
+                    &mut reader,
+                    layout,
+                    &payload_header,
+                    header.payload_offset,
+                )?)
+            }
+            None => None,
+        };
+
+        Ok(ChunkParser {
+            reader,
+            payload_start: header.payload_offset,
+            payload_end: header.payload_end,
+            cursor: header.payload_offset,
+            legacy_chunks,
+            recovery: None,
+            skipped: BTreeMap::new(),
+        })
+    }
+
+    pub fn recover(&mut self) {
+        self.recovery.get_or_insert_with(RecoveryReport::default);
+    }
+
+    pub fn recovery(&mut self) -> Option<&mut RecoveryReport> {
+        self.recovery.as_mut()
+    }
+
+    pub fn into_recovery_report(self) -> RecoveryReport {
+        let mut report = self.recovery.unwrap_or_default();
+
+        // damage at the very end of the payload, no chunk follows it
+        if let Some(damage) = self.skipped.get(&u64::MAX) {
+            report.record(damage);
         }
+
+        report
     }
 
-    fn parse_chunk_header(&mut self) -> Option<(u32, u8, u32, u32, u32)> {
-        Some((
-            parse_u32(&mut self.replay_content).unwrap(),
-            self.replay_content.next()?,
-            parse_u32(&mut self.replay_content).unwrap(),
-            parse_u32(&mut self.replay_content).unwrap(),
-            parse_u32(&mut self.replay_content).unwrap(),
+    fn parse_chunk_header(&mut self) -> ParseResult<(u32, u8, u32, u32, u32)> {
+        let mut header = [0u8; CHUNK_HEADER_SIZE];
+        self.reader
+            .read_exact(&mut header)
+            .map_err(|e| ParseError::from(e).at(self.cursor))?;
+
+        Ok((
+            u32::from_le_bytes(header[0..4].try_into().unwrap()),
+            header[4],
+            u32::from_le_bytes(header[5..9].try_into().unwrap()),
+            u32::from_le_bytes(header[9..13].try_into().unwrap()),
+            u32::from_le_bytes(header[13..17].try_into().unwrap()),
         ))
     }
 
-    pub fn next_chunk(&mut self) -> Option<Chunk> {
-        if self.replay_len <= self.cursor as u32 {
-            return None;
+    // reads the next chunk header and skips over its payload
+    pub fn next_chunk_header(&mut self) -> ParseResult<Option<ChunkHeader>> {
+        if let Some(legacy_chunks) = self.legacy_chunks.as_mut() {
+            // v1 chunk offsets come from the table, a damaged chunk can't hide the next one
+            return Ok(legacy_chunks.next_header());
         }
 
-        let (chunk_id, chunk_type, chunk_id_2, chunk_uncompressed_len, chunk_compressed_len) =
-            self.parse_chunk_header()?;
+        if self.payload_end <= self.cursor {
+            return Ok(None);
+        }
+
+        let header_offset = self.cursor;
+        match self.read_chunk_header() {
+            Err(e) if self.recovery.is_some() => {
+                let header = self.resync(header_offset + 1)?;
+                let next_offset = header.as_ref().map_or(u64::MAX, |h| h.payload_offset);
+                self.skipped.entry(next_offset).or_insert(e);
+                Ok(header)
+            }
+            result => result,
+        }
+    }
 
-        self.cursor += 0x11;
+    fn read_chunk_header(&mut self) -> ParseResult<Option<ChunkHeader>> {
+        self.reader.seek(SeekFrom::Start(self.cursor))?;
 
-        let payload: Option<Vec<u8>> = if chunk_compressed_len != 0 {
-            let compressed_payload: Vec<u8> = self
-                .replay_content
-                .by_ref()
-                .take(chunk_compressed_len as usize)
-                .collect();
+        let (chunk_id, chunk_type, chunk_id_2, chunk_uncompressed_len, mut chunk_compressed_len) =
+            self.parse_chunk_header()?;
 
-            self.cursor += chunk_compressed_len as usize;
+        let payload_offset = self.cursor + CHUNK_HEADER_SIZE as u64;
 
-            let mut uncompressed_payload = Vec::new();
-            let mut decoder = Decoder::new(compressed_payload.as_slice()).unwrap();
-            decoder.read_to_end(&mut uncompressed_payload).unwrap();
+        if self.recovery.is_some() && chunk_compressed_len != 0 {
+            // a corrupted length sends us in the middle of nowhere, make sure a frame starts here
+            let mut magic = [0u8; 4];
+            self.reader
+                .read_exact(&mut magic)
+                .map_err(|e| ParseError::from(e).at(payload_offset))?;
+            if magic != ZSTD_MAGIC {
+                return Err(
+                    ParseError::invalid("zstd frame magic", format!("{:02x?}", magic))
+                        .at(payload_offset)
+                        .in_chunk(chunk_id),
+                );
+            }
 
-            Some(uncompressed_payload)
+            // a corrupted length would swallow the intact chunks after this one, end the frame
+            // at the next header found before it. the last chunk of a crashed game is cut off
+            // and has nothing after it, decompress what's there
+            let payload_end = payload_offset + chunk_compressed_len as u64;
+            if payload_end != self.payload_end && !self.is_chunk_header_at(payload_end)? {
+                let next_header = self
+                    .find_chunk_header(payload_offset + 1)?
+                    .unwrap_or(self.payload_end);
+                if next_header < payload_end {
+                    chunk_compressed_len = (next_header - payload_offset) as u32;
+                }
+            }
+        }
+
+        let payload_len = if chunk_compressed_len != 0 {
+            chunk_compressed_len
         } else {
-            self.replay_content.nth(chunk_uncompressed_len as usize - 1);
-            self.cursor += chunk_uncompressed_len as usize;
-            None
+            chunk_uncompressed_len
         };
 
-        Some(Chunk {
+        if payload_offset + payload_len as u64 > self.payload_end {
+            return Err(ParseError::eof("chunk payload")
+                .at(payload_offset)
+                .in_chunk(chunk_id));
+        }
+
+        self.cursor = payload_offset + payload_len as u64;
+
+        Ok(Some(ChunkHeader {
             id: chunk_id,
             type_: chunk_type,
             id_2: chunk_id_2,
             uncompressed_len: chunk_uncompressed_len,
             compressed_len: chunk_compressed_len,
-            payload,
-        })
+            payload_offset,
+        }))
+    }
+
+    // skips the damaged bytes up to the next chunk header, if there is one
+    fn resync(&mut self, from: u64) -> ParseResult<Option<ChunkHeader>> {
+        match self.find_chunk_header(from)? {
+            Some(offset) => {
+                self.cursor = offset;
+                self.next_chunk_header()
+            }
+            None => {
+                self.cursor = self.payload_end;
+                Ok(None)
+            }
+        }
+    }
+
+    // offset of the first chunk header at or after `from`, scanned a window at a time so
+    // memory stays bounded however long the damage is
+    fn find_chunk_header(&mut self, from: u64) -> ParseResult<Option<u64>> {
+        let mut window = Vec::with_capacity(SCAN_WINDOW_SIZE);
+        let mut start = from;
+
+        while start + CHUNK_PROBE_SIZE as u64 <= self.payload_end {
+            window.clear();
+            self.reader.seek(SeekFrom::Start(start))?;
+            (&mut self.reader)
+                .take((self.payload_end - start).min(SCAN_WINDOW_SIZE as u64))
+                .read_to_end(&mut window)?;
+            if window.len() < CHUNK_PROBE_SIZE {
+                break;
+            }
+
+            let found = (0..=window.len() - CHUNK_PROBE_SIZE)
+                .map(|i| start + i as u64)
+                .zip(window.windows(CHUNK_PROBE_SIZE))
+                .find(|&(offset, bytes)| {
+                    Self::looks_like_chunk_header(bytes) && self.fits_in_payload(offset, bytes)
+                });
+            if let Some((offset, _)) = found {
+                return Ok(Some(offset));
+            }
+
+            // a header may start in the last bytes of the window and end in the next one
+            start += (window.len() - CHUNK_PROBE_SIZE + 1) as u64;
+        }
+
+        Ok(None)
+    }
+
+    // whether the chunk before `offset` really ends there, its own length is checked once read
+    fn is_chunk_header_at(&mut self, offset: u64) -> ParseResult<bool> {
+        if offset + CHUNK_PROBE_SIZE as u64 > self.payload_end {
+            return Ok(false);
+        }
+
+        let mut bytes = [0u8; CHUNK_PROBE_SIZE];
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.reader.read_exact(&mut bytes)?;
+
+        Ok(Self::looks_like_chunk_header(&bytes))
+    }
+
+    fn looks_like_chunk_header(bytes: &[u8]) -> bool {
+        let chunk_type = bytes[4];
+        let compressed_len = u32::from_le_bytes(bytes[13..17].try_into().unwrap());
+
+        matches!(chunk_type, 0x1 | KEYFRAME_CHUNK_TYPE)
+            && compressed_len != 0
+            && bytes[CHUNK_HEADER_SIZE..CHUNK_PROBE_SIZE] == ZSTD_MAGIC
+    }
+
+    // a header found by scanning must also hold a frame that fits in the payload
+    fn fits_in_payload(&self, offset: u64, bytes: &[u8]) -> bool {
+        let compressed_len = u32::from_le_bytes(bytes[13..17].try_into().unwrap());
+        offset + CHUNK_HEADER_SIZE as u64 + compressed_len as u64 <= self.payload_end
+    }
+
+    // reads the still compressed payload, decoding it is left to RawChunk::decode
+    pub fn read_raw_chunk(&mut self, header: &ChunkHeader) -> ParseResult<RawChunk> {
+        let mut raw_chunk = RawChunk {
+            header: header.clone(),
+            data: None,
+            chunk_key: self
+                .legacy_chunks
+                .as_ref()
+                .map(|legacy_chunks| legacy_chunks.chunk_key().to_vec()),
+            recover: self.recovery.is_some(),
+            skipped: self.take_skipped(header),
+            damage: None,
+        };
+
+        if header.compressed_len == 0 {
+            return Ok(raw_chunk);
+        }
+
+        // v1 lengths come straight from the chunk table, check them before allocating
+        if header.payload_offset + header.compressed_len as u64 > self.payload_end {
+            let error = ParseError::invalid(
+                "chunk length",
+                format!(
+                    "{} at 0x{:x} (payload ends at 0x{:x})",
+                    header.compressed_len, header.payload_offset, self.payload_end
+                ),
+            )
+            .at(header.payload_offset)
+            .in_chunk(header.id);
+            if !raw_chunk.recover {
+                return Err(error);
+            }
+            raw_chunk.damage = Some(error);
+            return Ok(raw_chunk);
+        }
+
+        let mut data = vec![0u8; header.compressed_len as usize];
+        self.reader.seek(SeekFrom::Start(header.payload_offset))?;
+        match self.reader.read_exact(&mut data) {
+            Ok(()) => raw_chunk.data = Some(data),
+            Err(e) => {
+                let error = ParseError::from(e)
+                    .at(header.payload_offset)
+                    .in_chunk(header.id);
+                if !raw_chunk.recover {
+                    return Err(error);
+                }
+                raw_chunk.damage = Some(error);
+            }
+        }
+
+        Ok(raw_chunk)
+    }
+
+    // damage skipped before `header`, including the one in front of chunks that were never read
+    fn take_skipped(&mut self, header: &ChunkHeader) -> Option<ParseError> {
+        let after = self.skipped.split_off(&(header.payload_offset + 1));
+        let before = std::mem::replace(&mut self.skipped, after);
+        before.into_values().next()
+    }
+
+    pub fn read_chunk(&mut self, header: &ChunkHeader) -> ParseResult<Chunk> {
+        self.read_raw_chunk(header)?.decode()
+    }
+
+    pub fn next_chunk(&mut self) -> ParseResult<Option<Chunk>> {
+        match self.next_chunk_header()? {
+            Some(header) => Ok(Some(self.read_chunk(&header)?)),
+            None => Ok(None),
+        }
+    }
+
+    // timestamp of the first block, decoding only the beginning of the payload
+    pub fn chunk_start_time(&mut self, header: &ChunkHeader) -> ParseResult<Option<f32>> {
+        match self.read_chunk_start_time(header) {
+            // reading the chunk itself will report the damage
+            Err(_) if self.recovery.is_some() => Ok(None),
+            result => result,
+        }
+    }
+
+    fn read_chunk_start_time(&mut self, header: &ChunkHeader) -> ParseResult<Option<f32>> {
+        let prefix = if let Some(legacy_chunks) = self.legacy_chunks.as_ref() {
+            legacy_chunks.read_payload_prefix(&mut self.reader, header, BLOCK_HEADER_MAX_SIZE)?
+        } else {
+            if header.compressed_len == 0 {
+                return Ok(None);
+            }
+
+            self.reader.seek(SeekFrom::Start(header.payload_offset))?;
+            let compressed_payload = (&mut self.reader).take(header.compressed_len as u64);
+
+            let mut prefix = Vec::with_capacity(BLOCK_HEADER_MAX_SIZE);
+            Decoder::new(compressed_payload)
+                .and_then(|decoder| {
+                    decoder
+                        .take(BLOCK_HEADER_MAX_SIZE as u64)
+                        .read_to_end(&mut prefix)
+                })
+                .map_err(|e| {
+                    ParseError::decompress(e)
+                        .at(header.payload_offset)
+                        .in_chunk(header.id)
+                })?;
+            prefix
+        };
+
+        // the prefix usually ends in the middle of the first payload, only the header matters
+        Ok(BlockParser::new(&prefix)
+            .next_block_header()
+            .map_err(|e| e.in_chunk(header.id))?
+            .map(|block| block.timestamp))
+    }
+
+    // makes `header` the next chunk returned by the parser
+    pub fn seek_to(&mut self, header: &ChunkHeader) {
+        if let Some(legacy_chunks) = self.legacy_chunks.as_mut() {
+            legacy_chunks.seek_to(header);
+        } else {
+            self.cursor = header.payload_offset - CHUNK_HEADER_SIZE as u64;
+        }
+
+        // damage before the new position isn't part of what will be read
+        self.skipped = self.skipped.split_off(&(header.payload_offset + 1));
+    }
+
+    // jumps to the first chunk that may hold `timestamp`, see ReplayIndex::range_start. the
+    // keyframe before it isn't needed, blocks are self contained and keyframes are read from
+    // the chunks of the range
+    pub fn seek(&mut self, index: &ReplayIndex, timestamp: f32) -> bool {
+        let Some(chunk) = index.range_start(timestamp) else {
+            return false;
+        };
+
+        self.seek_to(&chunk.header);
+
+        true
+    }
+
+    pub fn rewind(&mut self) {
+        if let Some(legacy_chunks) = self.legacy_chunks.as_mut() {
+            legacy_chunks.rewind();
+        } else {
+            self.cursor = self.payload_start;
+        }
+
+        // the damage will be found again on the next pass
+        if let Some(recovery) = self.recovery.as_mut() {
+            *recovery = RecoveryReport::default();
+        }
+        self.skipped.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::parser::writer::tests::{block, replay};
+
+    fn three_chunks() -> (Vec<u8>, Vec<ChunkHeader>) {
+        let raw = replay(&[
+            (1, 0x1, 0, vec![block(1.0, 1, 1)]),
+            (2, 0x1, 0, vec![block(31.0, 2, 2), block(32.0, 2, 3)]),
+            (3, 0x1, 0, vec![block(61.0, 3, 4)]),
+        ]);
+
+        let mut parser = ChunkParser::new(Cursor::new(raw.clone())).unwrap();
+        let headers = std::iter::from_fn(|| parser.next_chunk_header().unwrap()).collect();
+
+        (raw, headers)
+    }
+
+    // overwrites the compressed length of `header` in the file
+    fn corrupt_len(raw: &mut [u8], header: &ChunkHeader, len: u32) {
+        let offset = header.payload_offset as usize - 4;
+        raw[offset..offset + 4].copy_from_slice(&len.to_le_bytes());
+    }
+
+    // (id, block count, damaged) of every chunk read in recovery mode
+    fn recovered_chunks(raw: Vec<u8>) -> Vec<(u32, usize, bool)> {
+        let mut parser = ChunkParser::new(Cursor::new(raw)).unwrap();
+        parser.recover();
+
+        let mut chunks = Vec::new();
+        while let Some(chunk) = parser.next_chunk().unwrap() {
+            let payload = chunk.payload.unwrap();
+            let mut block_parser = BlockParser::new(&payload);
+            let mut block_count = 0;
+            while block_parser.next_block().unwrap().is_some() {
+                block_count += 1;
+            }
+            let damaged = chunk.skipped.is_some() || chunk.damage.is_some();
+            chunks.push((chunk.id, block_count, damaged));
+        }
+
+        chunks
+    }
+
+    #[test]
+    fn too_large_length_does_not_swallow_the_next_chunks() {
+        let (mut raw, headers) = three_chunks();
+        // bit flip in the top byte of chunk 2's length
+        corrupt_len(
+            &mut raw,
+            &headers[1],
+            headers[1].compressed_len | 0x0100_0000,
+        );
+
+        assert_eq!(
+            recovered_chunks(raw),
+            [(1, 1, false), (2, 2, false), (3, 1, false)]
+        );
+    }
+
+    #[test]
+    fn length_into_a_later_chunk_ends_at_the_next_header() {
+        let (mut raw, headers) = three_chunks();
+        // chunk 1 now ends in the middle of chunk 3's frame
+        let len = (headers[2].payload_offset - headers[0].payload_offset) as u32 + 4;
+        corrupt_len(&mut raw, &headers[0], len);
+
+        assert_eq!(
+            recovered_chunks(raw),
+            [(1, 1, false), (2, 2, false), (3, 1, false)]
+        );
+    }
+
+    #[test]
+    fn damaged_header_resyncs_on_the_next_chunk() {
+        let (mut raw, headers) = three_chunks();
+        // type byte of chunk 2
+        raw[headers[1].payload_offset as usize - 13] = 0x7;
+        let frame = headers[1].payload_offset as usize;
+        raw[frame..frame + 4].fill(0);
+
+        assert_eq!(recovered_chunks(raw), [(1, 1, false), (3, 1, true)]);
     }
 }
@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+use std::io::{Read, Seek};
+
+use crate::parser::block::Block;
+use crate::parser::error::ParseResult;
+use crate::parser::keyframe::Keyframe;
+use crate::parser::parser::{
+    for_each_block, for_each_block_with_keyframes, BlockPayload, ParseOptions,
+};
+use crate::parser::recovery::RecoveryReport;
+
+type Consumer<'c> = Box<dyn FnMut(&Block) + 'c>;
+
+// routes the blocks of a replay to the consumers of their packet id, in a single pass
+#[derive(Default)]
+pub struct BlockDemux<'c> {
+    consumers: HashMap<u16, Vec<Consumer<'c>>>,
+    // keyframe chunks are only decoded when someone wants them
+    keyframes: Option<&'c mut Vec<Keyframe>>,
+}
+
+impl<'c> BlockDemux<'c> {
+    pub fn on(&mut self, packet_id: u16, consumer: impl FnMut(&Block) + 'c) -> &mut Self {
+        self.consumers
+            .entry(packet_id)
+            .or_default()
+            .push(Box::new(consumer));
+        self
+    }
+
+    // copies the payloads out, for consumers that outlive the chunk buffers
+    pub fn collect(&mut self, packet_id: u16, payloads: &'c mut Vec<BlockPayload>) -> &mut Self {
+        self.on(packet_id, move |block| {
+            payloads.push((block.timestamp, block.payload.to_vec()))
+        })
+    }
+
+    // decodes the keyframes met on the way into `keyframes`
+    pub fn keyframes(&mut self, keyframes: &'c mut Vec<Keyframe>) -> &mut Self {
+        self.keyframes = Some(keyframes);
+        self
+    }
+
+    pub fn run<R: Read + Seek>(
+        mut self,
+        reader: R,
+        options: &ParseOptions,
+    ) -> ParseResult<RecoveryReport> {
+        let mut consumers = self.consumers;
+        let route = |block: &Block| {
+            if let Some(consumers) = consumers.get_mut(&block.packet_id) {
+                for consumer in consumers {
+                    consumer(block);
+                }
+            }
+        };
+
+        match self.keyframes.take() {
+            Some(keyframes) => for_each_block_with_keyframes(reader, options, keyframes, route),
+            None => for_each_block(reader, options, route),
+        }
+    }
+}
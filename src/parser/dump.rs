@@ -0,0 +1,113 @@
+use std::io::{self, Read, Seek, Write};
+use std::str::FromStr;
+
+use serde_json::json;
+
+use crate::parser::block::Block;
+use crate::parser::error::{ParseError, ParseResult};
+use crate::parser::parser::{for_each_block, ParseOptions};
+use crate::parser::util::to_hex;
+
+/*
+binary dump layout:
+    0x00    magic "RBLK"
+    records until EOF:
+        f32 timestamp
+        u16 packet id
+        u32 param
+        u32 payload length
+        payload
+*/
+
+pub const DUMP_MAGIC: [u8; 4] = *b"RBLK";
+
+#[derive(Debug, Clone, Copy)]
+pub enum DumpFormat {
+    Jsonl,
+    Binary,
+}
+
+impl FromStr for DumpFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" => Ok(DumpFormat::Jsonl),
+            "bin" | "binary" => Ok(DumpFormat::Binary),
+            _ => Err(format!(
+                "unknown dump format '{}', expected jsonl or bin",
+                s
+            )),
+        }
+    }
+}
+
+// empty lists let everything through
+#[derive(Debug, Clone, Default)]
+pub struct DumpFilter {
+    pub packet_ids: Vec<u16>,
+    pub params: Vec<u32>,
+}
+
+impl DumpFilter {
+    pub fn matches(&self, block: &Block) -> bool {
+        (self.packet_ids.is_empty() || self.packet_ids.contains(&block.packet_id))
+            && (self.params.is_empty() || self.params.contains(&block.param))
+    }
+}
+
+fn write_block<W: Write>(writer: &mut W, block: &Block, format: DumpFormat) -> io::Result<()> {
+    match format {
+        DumpFormat::Jsonl => {
+            let line = json!({
+                "timestamp": block.timestamp,
+                "packet_id": block.packet_id,
+                "param": block.param,
+                "length": block.length,
+                "payload": to_hex(&block.payload),
+            });
+            writeln!(writer, "{}", line)
+        }
+        DumpFormat::Binary => {
+            writer.write_all(&block.timestamp.to_le_bytes())?;
+            writer.write_all(&block.packet_id.to_le_bytes())?;
+            writer.write_all(&block.param.to_le_bytes())?;
+            writer.write_all(&(block.payload.len() as u32).to_le_bytes())?;
+            writer.write_all(&block.payload)
+        }
+    }
+}
+
+// returns the number of blocks written
+pub fn dump_blocks<R: Read + Seek, W: Write>(
+    reader: R,
+    mut writer: W,
+    options: &ParseOptions,
+    filter: &DumpFilter,
+    format: DumpFormat,
+) -> ParseResult<usize> {
+    if let DumpFormat::Binary = format {
+        writer.write_all(&DUMP_MAGIC)?;
+    }
+
+    let mut count = 0;
+    let mut write_error: Option<io::Error> = None;
+
+    for_each_block(reader, options, |block| {
+        if write_error.is_some() || !filter.matches(block) {
+            return;
+        }
+
+        match write_block(&mut writer, block, format) {
+            Ok(()) => count += 1,
+            Err(e) => write_error = Some(e),
+        }
+    })?;
+
+    if let Some(e) = write_error {
+        return Err(ParseError::from(e));
+    }
+    writer.flush()?;
+
+    Ok(count)
+}
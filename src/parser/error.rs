@@ -0,0 +1,147 @@
+use std::{fmt, io};
+
+#[derive(Debug)]
+pub enum ParseError {
+    UnexpectedEof {
+        expected: &'static str,
+        offset: Option<u64>,
+        chunk_id: Option<u32>,
+    },
+    InvalidValue {
+        expected: &'static str,
+        found: String,
+        offset: Option<u64>,
+        chunk_id: Option<u32>,
+    },
+    Decompress {
+        source: io::Error,
+        offset: Option<u64>,
+        chunk_id: Option<u32>,
+    },
+    Io {
+        source: io::Error,
+        offset: Option<u64>,
+    },
+}
+
+pub type ParseResult<T> = Result<T, ParseError>;
+
+impl ParseError {
+    pub fn eof(expected: &'static str) -> Self {
+        ParseError::UnexpectedEof {
+            expected,
+            offset: None,
+            chunk_id: None,
+        }
+    }
+
+    pub fn invalid(expected: &'static str, found: impl fmt::Display) -> Self {
+        ParseError::InvalidValue {
+            expected,
+            found: found.to_string(),
+            offset: None,
+            chunk_id: None,
+        }
+    }
+
+    pub fn decompress(source: io::Error) -> Self {
+        ParseError::Decompress {
+            source,
+            offset: None,
+            chunk_id: None,
+        }
+    }
+
+    // keeps the innermost offset, which is the most precise one
+    pub fn at(mut self, at: u64) -> Self {
+        match &mut self {
+            ParseError::UnexpectedEof { offset, .. }
+            | ParseError::InvalidValue { offset, .. }
+            | ParseError::Decompress { offset, .. }
+            | ParseError::Io { offset, .. } => {
+                offset.get_or_insert(at);
+            }
+        }
+        self
+    }
+
+    pub fn in_chunk(mut self, id: u32) -> Self {
+        match &mut self {
+            ParseError::UnexpectedEof { chunk_id, .. }
+            | ParseError::InvalidValue { chunk_id, .. }
+            | ParseError::Decompress { chunk_id, .. } => {
+                chunk_id.get_or_insert(id);
+            }
+            ParseError::Io { .. } => {}
+        }
+        self
+    }
+
+    pub fn offset(&self) -> Option<u64> {
+        match self {
+            ParseError::UnexpectedEof { offset, .. }
+            | ParseError::InvalidValue { offset, .. }
+            | ParseError::Decompress { offset, .. }
+            | ParseError::Io { offset, .. } => *offset,
+        }
+    }
+
+    pub fn chunk_id(&self) -> Option<u32> {
+        match self {
+            ParseError::UnexpectedEof { chunk_id, .. }
+            | ParseError::InvalidValue { chunk_id, .. }
+            | ParseError::Decompress { chunk_id, .. } => *chunk_id,
+            ParseError::Io { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnexpectedEof { expected, .. } => {
+                write!(f, "Unexpected end of data, expected {}", expected)?
+            }
+            ParseError::InvalidValue {
+                expected, found, ..
+            } => write!(f, "Invalid {}: {}", expected, found)?,
+            ParseError::Decompress { source, .. } => {
+                write!(f, "Failed to decompress chunk: {}", source)?
+            }
+            ParseError::Io { source, .. } => write!(f, "I/O error: {}", source)?,
+        }
+
+        if let Some(chunk_id) = self.chunk_id() {
+            write!(f, " (chunk {})", chunk_id)?;
+        }
+
+        if let Some(offset) = self.offset() {
+            write!(f, " (offset 0x{:x})", offset)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError::Decompress { source, .. } | ParseError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(source: io::Error) -> Self {
+        // a short read on a replay is a truncated file, not an I/O failure
+        if source.kind() == io::ErrorKind::UnexpectedEof {
+            return ParseError::eof("more replay data");
+        }
+
+        ParseError::Io {
+            source,
+            offset: None,
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::parser::error::{ParseError, ParseResult};
+use serde_json::Value;
+
+use crate::parser::legacy::{
+    LegacyLayout, LEGACY_FORMAT_VERSION, LEGACY_HEADER_OFFSET, LEGACY_SIGNATURE_OFFSET,
+};
+
+/*
+ROFL2 layout:
+    0x00    magic "RIOT"
+    0x04    u16 format version (2)
+    0x06    8 bytes, not decoded yet
+    0x0E    u8 game version length
+    0x0F    game version ("15.4.658.1234")
+    ...     chunks (payload)
+    ...     signature (0x100 bytes)
+    ...     metadata json
+    EOF-4   u32 metadata length
+
+ROFL v1 files share the magic, followed by a zero format version (see legacy.rs).
+*/
+
+pub const ROFL_MAGIC: [u8; 4] = *b"RIOT";
+pub const ROFL2_FORMAT_VERSION: u16 = 2;
+
+pub const SIGNATURE_SIZE: u64 = 0x100;
+
+#[derive(Debug, Clone)]
+pub struct RoflHeader {
+    pub magic: [u8; 4],
+    pub format_version: u16,
+    pub unknown: [u8; 8],
+    pub game_version: String,
+
+    pub payload_offset: u64,
+    pub payload_end: u64,
+
+    pub signature: Vec<u8>,
+
+    pub metadata_offset: u64,
+    pub metadata_len: u32,
+
+    // only set for ROFL v1 replays
+    pub legacy: Option<LegacyLayout>,
+}
+
+impl RoflHeader {
+    const GAME_VERSION_LEN_OFFSET: u64 = 0x0E;
+
+    pub fn read<R: Read + Seek>(reader: &mut R) -> ParseResult<Self> {
+        let replay_len = reader.seek(SeekFrom::End(0))?;
+        if replay_len < Self::GAME_VERSION_LEN_OFFSET + 1 + SIGNATURE_SIZE + 4 {
+            return Err(ParseError::invalid(
+                "replay size",
+                format!("{} bytes", replay_len),
+            ));
+        }
+
+        reader.seek(SeekFrom::Start(0))?;
+
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != ROFL_MAGIC {
+            return Err(ParseError::invalid("magic \"RIOT\"", format!("{:?}", magic)).at(0));
+        }
+
+        let mut raw_format_version = [0u8; 2];
+        reader.read_exact(&mut raw_format_version)?;
+        let format_version = u16::from_le_bytes(raw_format_version);
+
+        match format_version {
+            ROFL2_FORMAT_VERSION => Self::read_rofl2(reader, replay_len, magic, format_version),
+            LEGACY_FORMAT_VERSION => Self::read_legacy(reader, replay_len, magic, format_version),
+            _ => Err(ParseError::invalid("ROFL format version", format_version).at(4)),
+        }
+    }
+
+    fn read_rofl2<R: Read + Seek>(
+        reader: &mut R,
+        replay_len: u64,
+        magic: [u8; 4],
+        format_version: u16,
+    ) -> ParseResult<Self> {
+        let mut unknown = [0u8; 8];
+        reader.read_exact(&mut unknown)?;
+
+        let mut game_version_len = [0u8; 1];
+        reader.read_exact(&mut game_version_len)?;
+
+        let mut raw_game_version = vec![0u8; game_version_len[0] as usize];
+        reader.read_exact(&mut raw_game_version)?;
+        let game_version = match String::from_utf8(raw_game_version) {
+            Ok(version) if Self::is_valid_game_version(&version) => version,
+            _ => {
+                return Err(ParseError::invalid(
+                    "game version",
+                    format!("{} bytes of garbage", game_version_len[0]),
+                )
+                .at(Self::GAME_VERSION_LEN_OFFSET + 1))
+            }
+        };
+
+        let payload_offset = Self::GAME_VERSION_LEN_OFFSET + 1 + game_version_len[0] as u64;
+
+        reader.seek(SeekFrom::End(-4))?;
+        let mut raw_metadata_len = [0u8; 4];
+        reader.read_exact(&mut raw_metadata_len)?;
+        let metadata_len = u32::from_le_bytes(raw_metadata_len);
+
+        let trailer_len = metadata_len as u64 + SIGNATURE_SIZE + 4;
+        if payload_offset + trailer_len > replay_len {
+            return Err(ParseError::invalid(
+                "metadata length",
+                format!("{} (replay is {} bytes)", metadata_len, replay_len),
+            )
+            .at(replay_len - 4));
+        }
+
+        let metadata_offset = replay_len - 4 - metadata_len as u64;
+        let payload_end = metadata_offset - SIGNATURE_SIZE;
+
+        let mut signature = vec![0u8; SIGNATURE_SIZE as usize];
+        reader.seek(SeekFrom::Start(payload_end))?;
+        reader.read_exact(&mut signature)?;
+
+        Ok(RoflHeader {
+            magic,
+            format_version,
+            unknown,
+            game_version,
+            payload_offset,
+            payload_end,
+            signature,
+            metadata_offset,
+            metadata_len,
+            legacy: None,
+        })
+    }
+
+    fn read_legacy<R: Read + Seek>(
+        reader: &mut R,
+        replay_len: u64,
+        magic: [u8; 4],
+        format_version: u16,
+    ) -> ParseResult<Self> {
+        let mut signature = vec![0u8; SIGNATURE_SIZE as usize];
+        reader.seek(SeekFrom::Start(LEGACY_SIGNATURE_OFFSET))?;
+        reader.read_exact(&mut signature)?;
+
+        let (layout, metadata_offset, metadata_len, payload_offset) = LegacyLayout::read(reader)?;
+
+        if layout.file_len as u64 != replay_len {
+            return Err(ParseError::invalid(
+                "legacy file length",
+                format!("{} (replay is {} bytes)", layout.file_len, replay_len),
+            )
+            .at(LEGACY_HEADER_OFFSET + 2));
+        }
+
+        if metadata_offset + metadata_len as u64 > replay_len || payload_offset > replay_len {
+            return Err(ParseError::invalid(
+                "legacy offsets",
+                format!(
+                    "metadata 0x{:x}+{}, payload 0x{:x}",
+                    metadata_offset, metadata_len, payload_offset
+                ),
+            )
+            .at(LEGACY_HEADER_OFFSET + 6));
+        }
+
+        // v1 headers don't carry the game version, it lives in the metadata json
+        let mut raw_metadata = vec![0u8; metadata_len as usize];
+        reader.seek(SeekFrom::Start(metadata_offset))?;
+        reader.read_exact(&mut raw_metadata)?;
+
+        let metadata: Value = serde_json::from_slice(&raw_metadata)
+            .map_err(|e| ParseError::invalid("metadata json", e).at(metadata_offset))?;
+        let game_version = match metadata["gameVersion"].as_str() {
+            Some(version) if Self::is_valid_game_version(version) => version.to_string(),
+            found => {
+                return Err(
+                    ParseError::invalid("gameVersion", format!("{:?}", found)).at(metadata_offset)
+                )
+            }
+        };
+
+        Ok(RoflHeader {
+            magic,
+            format_version,
+            unknown: [0u8; 8],
+            game_version,
+            payload_offset,
+            payload_end: replay_len,
+            signature,
+            metadata_offset,
+            metadata_len,
+            legacy: Some(layout),
+        })
+    }
+
+    pub fn read_metadata<R: Read + Seek>(&self, reader: &mut R) -> ParseResult<Vec<u8>> {
+        let mut raw_metadata = vec![0u8; self.metadata_len as usize];
+        reader.seek(SeekFrom::Start(self.metadata_offset))?;
+        reader
+            .read_exact(&mut raw_metadata)
+            .map_err(|e| ParseError::from(e).at(self.metadata_offset))?;
+
+        Ok(raw_metadata)
+    }
+
+    // always writes a ROFL2 header, v1 replays get upgraded on the way
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&ROFL_MAGIC)?;
+        writer.write_all(&ROFL2_FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&self.unknown)?;
+        writer.write_all(&[self.game_version.len() as u8])?;
+        writer.write_all(self.game_version.as_bytes())
+    }
+
+    fn is_valid_game_version(version: &str) -> bool {
+        !version.is_empty() && version.chars().all(|c| c.is_ascii_digit() || c == '.')
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::parser::writer::tests::{block, replay, METADATA};
+
+    // v1 header pointing at `metadata`, with an empty payload header and payload behind it
+    fn legacy_replay(metadata: &[u8]) -> Vec<u8> {
+        let metadata_offset = 0x120u32;
+        let payload_header_offset = metadata_offset + metadata.len() as u32;
+        let file_len = payload_header_offset + 0x22;
+
+        let mut raw = b"RIOT\0\0".to_vec();
+        raw.resize(LEGACY_HEADER_OFFSET as usize, 0x55);
+        raw.extend_from_slice(&0x1Au16.to_le_bytes());
+        for field in [
+            file_len,
+            metadata_offset,
+            metadata.len() as u32,
+            payload_header_offset,
+            0x22,
+            file_len,
+        ] {
+            raw.extend_from_slice(&field.to_le_bytes());
+        }
+        raw.resize(metadata_offset as usize, 0);
+        raw.extend_from_slice(metadata);
+        raw.resize(file_len as usize, 0);
+        raw
+    }
+
+    #[test]
+    fn reads_rofl2_layout() {
+        let raw = replay(&[(1, 0x1, 0, vec![block(1.0, 1, 1)])]);
+        let len = raw.len() as u64;
+
+        let header = RoflHeader::read(&mut Cursor::new(raw)).unwrap();
+
+        assert_eq!(header.format_version, ROFL2_FORMAT_VERSION);
+        assert_eq!(header.game_version, "15.4.658.1234");
+        assert_eq!(header.payload_offset, 0x0F + "15.4.658.1234".len() as u64);
+        assert_eq!(header.metadata_len as usize, METADATA.len());
+        assert_eq!(header.metadata_offset, len - 4 - METADATA.len() as u64);
+        assert_eq!(header.payload_end, header.metadata_offset - SIGNATURE_SIZE);
+        assert!(header.legacy.is_none());
+    }
+
+    #[test]
+    fn rejects_bad_rofl2_headers() {
+        let raw = replay(&[]);
+
+        let mut bad_magic = raw.clone();
+        bad_magic[0] = b'X';
+        assert!(RoflHeader::read(&mut Cursor::new(bad_magic)).is_err());
+
+        let mut bad_version = raw.clone();
+        bad_version[4] = 7;
+        assert!(RoflHeader::read(&mut Cursor::new(bad_version)).is_err());
+
+        // metadata length pointing before the start of the file
+        let mut bad_metadata_len = raw.clone();
+        let len = bad_metadata_len.len();
+        bad_metadata_len[len - 4..].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(RoflHeader::read(&mut Cursor::new(bad_metadata_len)).is_err());
+
+        assert!(RoflHeader::read(&mut Cursor::new(raw[..0x20].to_vec())).is_err());
+    }
+
+    #[test]
+    fn reads_legacy_layout() {
+        let metadata = br#"{"gameVersion": "7.14.194.5087"}"#;
+        let raw = legacy_replay(metadata);
+        let len = raw.len() as u64;
+
+        let header = RoflHeader::read(&mut Cursor::new(raw)).unwrap();
+
+        assert_eq!(header.format_version, LEGACY_FORMAT_VERSION);
+        assert_eq!(header.game_version, "7.14.194.5087");
+        assert_eq!(header.metadata_offset, 0x120);
+        assert_eq!(header.payload_offset, len);
+        assert_eq!(header.payload_end, len);
+        assert_eq!(header.signature, vec![0x55; SIGNATURE_SIZE as usize]);
+        assert_eq!(header.legacy.unwrap().file_len as u64, len);
+    }
+
+    #[test]
+    fn rejects_bad_legacy_headers() {
+        // file length that doesn't match the file
+        let mut raw = legacy_replay(br#"{"gameVersion": "7.14.194.5087"}"#);
+        raw.push(0);
+        assert!(RoflHeader::read(&mut Cursor::new(raw)).is_err());
+
+        let raw = legacy_replay(br#"{"gameVersion": "not a version"}"#);
+        assert!(RoflHeader::read(&mut Cursor::new(raw)).is_err());
+    }
+}
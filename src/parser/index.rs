@@ -0,0 +1,250 @@
+use std::io::{Read, Seek};
+
+use crate::parser::chunk::*;
+use crate::parser::error::ParseResult;
+use crate::parser::keyframe::KEYFRAME_CHUNK_TYPE;
+
+#[derive(Debug, Clone)]
+pub struct IndexEntry {
+    pub header: ChunkHeader,
+    pub start_time: f32,
+    pub end_time: f32,
+}
+
+impl IndexEntry {
+    fn new(header: &ChunkHeader, start_time: f32) -> Self {
+        IndexEntry {
+            header: header.clone(),
+            start_time,
+            end_time: f32::INFINITY,
+        }
+    }
+}
+
+// maps chunks and keyframes to the time range they cover, mostly from the headers alone
+#[derive(Debug, Default)]
+pub struct ReplayIndex {
+    pub chunks: Vec<IndexEntry>,
+    pub keyframes: Vec<IndexEntry>,
+}
+
+// spectator chunks cover 30 seconds of game each, ids count up from the first one
+const CHUNK_INTERVAL: f32 = 30.0;
+
+impl ReplayIndex {
+    pub fn build<R: Read + Seek>(chunk_parser: &mut ChunkParser<R>) -> ParseResult<Self> {
+        let mut headers: Vec<ChunkHeader> = Vec::new();
+        while let Some(header) = chunk_parser.next_chunk_header()? {
+            headers.push(header);
+        }
+
+        let mut index = match Self::from_ids(chunk_parser, &headers)? {
+            Some(index) => index,
+            None => Self::from_probes(chunk_parser, headers)?,
+        };
+
+        // each entry lasts until the next one of the same kind starts
+        for entries in [&mut index.chunks, &mut index.keyframes] {
+            for i in 1..entries.len() {
+                entries[i - 1].end_time = entries[i].start_time;
+            }
+        }
+
+        chunk_parser.rewind();
+
+        Ok(index)
+    }
+
+    // start times from the chunk ids and the fixed chunk length. only the startup chunks
+    // (id_2 is 0 until the first keyframe), the first game chunk and the last one, as a check,
+    // are decoded. None when the ids don't follow the usual layout
+    fn from_ids<R: Read + Seek>(
+        chunk_parser: &mut ChunkParser<R>,
+        headers: &[ChunkHeader],
+    ) -> ParseResult<Option<Self>> {
+        let (keyframes, chunks): (Vec<&ChunkHeader>, Vec<&ChunkHeader>) = headers
+            .iter()
+            .partition(|header| header.type_ == KEYFRAME_CHUNK_TYPE);
+
+        let game_start = chunks
+            .iter()
+            .position(|header| header.id_2 != 0)
+            .unwrap_or(chunks.len());
+        let (startup_chunks, game_chunks) = chunks.split_at(game_start);
+        if !game_chunks.windows(2).all(|pair| pair[0].id < pair[1].id) {
+            return Ok(None);
+        }
+
+        let mut index = ReplayIndex::default();
+        for &header in startup_chunks {
+            if let Some(start_time) = chunk_parser.chunk_start_time(header)? {
+                index.chunks.push(IndexEntry::new(header, start_time));
+            }
+        }
+
+        if let Some(&first) = game_chunks.first() {
+            let Some(first_start) = chunk_parser.chunk_start_time(first)? else {
+                return Ok(None);
+            };
+            let start_time =
+                |header: &ChunkHeader| first_start + (header.id - first.id) as f32 * CHUNK_INTERVAL;
+
+            let last = game_chunks[game_chunks.len() - 1];
+            if let Some(last_start) = chunk_parser.chunk_start_time(last)? {
+                if (last_start - start_time(last)).abs() > CHUNK_INTERVAL / 2.0 {
+                    return Ok(None);
+                }
+            }
+
+            for &header in game_chunks {
+                index
+                    .chunks
+                    .push(IndexEntry::new(header, start_time(header)));
+            }
+        }
+
+        // a keyframe is taken where the chunk it points to (id_2) starts
+        for &header in &keyframes {
+            let start_time = match index
+                .chunks
+                .iter()
+                .find(|chunk| chunk.header.id == header.id_2)
+            {
+                Some(chunk) => Some(chunk.start_time),
+                None => chunk_parser.chunk_start_time(header)?,
+            };
+            if let Some(start_time) = start_time {
+                index.keyframes.push(IndexEntry::new(header, start_time));
+            }
+        }
+
+        Ok(Some(index))
+    }
+
+    // decodes the first block of every chunk
+    fn from_probes<R: Read + Seek>(
+        chunk_parser: &mut ChunkParser<R>,
+        headers: Vec<ChunkHeader>,
+    ) -> ParseResult<Self> {
+        let mut index = ReplayIndex::default();
+        for header in headers {
+            let Some(start_time) = chunk_parser.chunk_start_time(&header)? else {
+                continue;
+            };
+
+            let entry = IndexEntry::new(&header, start_time);
+            if header.type_ == KEYFRAME_CHUNK_TYPE {
+                index.keyframes.push(entry);
+            } else {
+                index.chunks.push(entry);
+            }
+        }
+
+        Ok(index)
+    }
+
+    // first chunk to read for a range starting at `timestamp`. start times estimated from the
+    // ids can be off in either direction, so reading starts one chunk before the one that
+    // seems to hold `timestamp` and the blocks are filtered by their own timestamps
+    pub fn range_start(&self, timestamp: f32) -> Option<&IndexEntry> {
+        let i = self
+            .chunks
+            .partition_point(|entry| entry.start_time <= timestamp);
+        self.chunks.get(i.saturating_sub(2))
+    }
+
+    // whether nothing in `header` can be before `end`: for the same reason, only once the
+    // chunk before it starts after `end`
+    pub fn is_past(&self, header: &ChunkHeader, end: f32) -> bool {
+        let entries = self.entries(header);
+        entries
+            .binary_search_by_key(&header.payload_offset, |entry| entry.header.payload_offset)
+            .is_ok_and(|i| i > 0 && entries[i - 1].start_time > end)
+    }
+
+    fn entries(&self, header: &ChunkHeader) -> &[IndexEntry] {
+        if header.type_ == KEYFRAME_CHUNK_TYPE {
+            &self.keyframes
+        } else {
+            &self.chunks
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::parser::block::Block;
+    use crate::parser::writer::tests::{block, replay};
+
+    // (id, id_2, block timestamps). chunk 4 really starts at 95 instead of 90 and chunk 5 at
+    // 118 instead of 120, which estimates from the ids can't know
+    pub const UNEVEN_CHUNKS: [(u32, u32, &[f32]); 6] = [
+        (1, 0, &[0.5]),
+        (2, 1, &[30.0, 40.0, 50.0]),
+        (3, 1, &[60.0, 70.0, 80.0, 93.0]),
+        (4, 1, &[95.0, 105.0, 115.0]),
+        (5, 1, &[118.0, 128.0, 138.0]),
+        (6, 1, &[150.0, 160.0]),
+    ];
+
+    pub fn uneven_replay(chunks: &[(u32, u32, &[f32])]) -> Vec<u8> {
+        let chunks: Vec<(u32, u8, u32, Vec<Block>)> = chunks
+            .iter()
+            .map(|&(id, id_2, timestamps)| {
+                let blocks = timestamps.iter().map(|&t| block(t, 1, t as u32)).collect();
+                (id, 0x1, id_2, blocks)
+            })
+            .collect();
+        replay(&chunks)
+    }
+
+    fn start_times(chunks: &[(u32, u32, &[f32])]) -> Vec<f32> {
+        let mut chunk_parser = ChunkParser::new(Cursor::new(uneven_replay(chunks))).unwrap();
+        let index = ReplayIndex::build(&mut chunk_parser).unwrap();
+        index.chunks.iter().map(|entry| entry.start_time).collect()
+    }
+
+    #[test]
+    fn estimates_start_times_from_ids() {
+        assert_eq!(
+            start_times(&UNEVEN_CHUNKS),
+            [0.5, 30.0, 60.0, 90.0, 120.0, 150.0]
+        );
+    }
+
+    #[test]
+    fn probes_every_chunk_when_ids_are_out_of_order() {
+        let mut chunks = UNEVEN_CHUNKS;
+        chunks[3].0 = 7;
+        assert_eq!(start_times(&chunks), [0.5, 30.0, 60.0, 95.0, 118.0, 150.0]);
+    }
+
+    #[test]
+    fn probes_every_chunk_when_the_last_estimate_is_off() {
+        let mut chunks = UNEVEN_CHUNKS;
+        chunks[5].2 = &[170.0];
+        assert_eq!(start_times(&chunks), [0.5, 30.0, 60.0, 95.0, 118.0, 170.0]);
+    }
+
+    #[test]
+    fn ranges_start_a_chunk_early_and_end_a_chunk_late() {
+        let mut chunk_parser =
+            ChunkParser::new(Cursor::new(uneven_replay(&UNEVEN_CHUNKS))).unwrap();
+        let index = ReplayIndex::build(&mut chunk_parser).unwrap();
+
+        // 92 seems to be in chunk 4, but chunk 3 holds a block at 93
+        assert_eq!(index.range_start(92.0).unwrap().header.id, 3);
+        assert_eq!(index.range_start(0.0).unwrap().header.id, 1);
+        assert_eq!(index.range_start(1000.0).unwrap().header.id, 5);
+
+        // chunk 5 seems to start after 119, but it holds a block at 118
+        let chunk_5 = &index.chunks[4].header;
+        let chunk_6 = &index.chunks[5].header;
+        assert!(!index.is_past(chunk_5, 119.0));
+        assert!(index.is_past(chunk_6, 119.0));
+        assert!(!index.is_past(chunk_6, 121.0));
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::BTreeMap;
+use std::fmt as display;
+use std::io::{Read, Seek};
+
+use serde::Serialize;
+
+use crate::parser::block::*;
+use crate::parser::chunk::*;
+use crate::parser::error::ParseResult;
+
+#[derive(Debug, Serialize)]
+pub struct ChunkStats {
+    pub id: u32,
+    #[serde(rename = "type")]
+    pub type_: u8,
+    pub id_2: u32,
+    pub compressed_len: u32,
+    pub uncompressed_len: u32,
+    pub block_count: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PacketStats {
+    pub packet_id: u16,
+    pub count: usize,
+    pub min_len: u32,
+    pub max_len: u32,
+    pub total_len: u64,
+    pub first_timestamp: f32,
+    pub last_timestamp: f32,
+}
+
+// params are entity net ids for most packets
+#[derive(Debug, Serialize)]
+pub struct ParamStats {
+    pub param: u32,
+    pub count: usize,
+    // packet id -> count
+    pub packets: BTreeMap<u16, usize>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ReplayStats {
+    pub chunks: Vec<ChunkStats>,
+    pub packets: Vec<PacketStats>,
+    pub params: Vec<ParamStats>,
+}
+
+impl ReplayStats {
+    // every chunk and block of the replay, keyframes included
+    pub fn collect<R: Read + Seek>(reader: R) -> ParseResult<Self> {
+        let mut stats = ReplayStats::default();
+        let mut packets: BTreeMap<u16, PacketStats> = BTreeMap::new();
+        let mut params: BTreeMap<u32, ParamStats> = BTreeMap::new();
+
+        let mut chunk_parser = ChunkParser::new(reader)?;
+        while let Some(chunk) = chunk_parser.next_chunk()? {
+            let mut block_count = 0;
+
+            if let Some(payload) = &chunk.payload {
+                let mut block_parser = BlockParser::new(payload);
+                while let Some(block) = block_parser
+                    .next_block()
+                    .map_err(|e| e.in_chunk(chunk.id))?
+                {
+                    Self::add_block(&mut packets, &mut params, &block);
+                    block_count += 1;
+                }
+            }
+
+            stats.chunks.push(ChunkStats {
+                id: chunk.id,
+                type_: chunk.type_,
+                id_2: chunk.id_2,
+                compressed_len: chunk.compressed_len,
+                uncompressed_len: chunk.uncompressed_len,
+                block_count,
+            });
+        }
+
+        stats.packets = packets.into_values().collect();
+        stats.params = params.into_values().collect();
+
+        Ok(stats)
+    }
+
+    fn add_block(
+        packets: &mut BTreeMap<u16, PacketStats>,
+        params: &mut BTreeMap<u32, ParamStats>,
+        block: &Block,
+    ) {
+        let packet = packets
+            .entry(block.packet_id)
+            .or_insert_with(|| PacketStats {
+                packet_id: block.packet_id,
+                count: 0,
+                min_len: u32::MAX,
+                max_len: 0,
+                total_len: 0,
+                first_timestamp: block.timestamp,
+                last_timestamp: block.timestamp,
+            });
+        packet.count += 1;
+        packet.min_len = packet.min_len.min(block.length);
+        packet.max_len = packet.max_len.max(block.length);
+        packet.total_len += block.length as u64;
+        packet.first_timestamp = packet.first_timestamp.min(block.timestamp);
+        packet.last_timestamp = packet.last_timestamp.max(block.timestamp);
+
+        let param = params.entry(block.param).or_insert_with(|| ParamStats {
+            param: block.param,
+            count: 0,
+            packets: BTreeMap::new(),
+        });
+        param.count += 1;
+        *param.packets.entry(block.packet_id).or_default() += 1;
+    }
+}
+
+impl display::Display for ReplayStats {
+    fn fmt(&self, f: &mut display::Formatter<'_>) -> display::Result {
+        writeln!(f, "Chunks ({})", self.chunks.len())?;
+        writeln!(
+            f,
+            "{:>8} {:>6} {:>8} {:>12} {:>12} {:>8}",
+            "id", "type", "id_2", "compressed", "uncompressed", "blocks"
+        )?;
+        for chunk in &self.chunks {
+            writeln!(
+                f,
+                "{:>8} {:>6} {:>8} {:>12} {:>12} {:>8}",
+                chunk.id,
+                chunk.type_,
+                chunk.id_2,
+                chunk.compressed_len,
+                chunk.uncompressed_len,
+                chunk.block_count
+            )?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "Packets ({})", self.packets.len())?;
+        writeln!(
+            f,
+            "{:>9} {:>8} {:>8} {:>8} {:>10} {:>10}",
+            "packet_id", "count", "min_len", "max_len", "first", "last"
+        )?;
+        for packet in &self.packets {
+            writeln!(
+                f,
+                "{:>9} {:>8} {:>8} {:>8} {:>10.3} {:>10.3}",
+                packet.packet_id,
+                packet.count,
+                packet.min_len,
+                packet.max_len,
+                packet.first_timestamp,
+                packet.last_timestamp
+            )?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "Params ({})", self.params.len())?;
+        writeln!(f, "{:>10} {:>8}  packets (id x count)", "param", "count")?;
+        for param in &self.params {
+            let packets = param
+                .packets
+                .iter()
+                .map(|(packet_id, count)| format!("{}x{}", packet_id, count))
+                .collect::<Vec<_>>()
+                .join(" ");
+            writeln!(f, "0x{:08x} {:>8}  {}", param.param, param.count, packets)?;
+        }
+
+        Ok(())
+    }
+}
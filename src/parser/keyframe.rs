@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+
+use crate::parser::block::*;
+use crate::parser::chunk::*;
+use crate::parser::error::ParseResult;
+
+pub const KEYFRAME_CHUNK_TYPE: u8 = 0x2;
+
+#[derive(Debug)]
+pub struct KeyframeEntity {
+    pub id: u32,
+    pub blocks: Vec<Block<'static>>,
+}
+
+// full game state snapshot, taken every ~60 seconds
+#[derive(Debug)]
+pub struct Keyframe {
+    pub id: u32,
+    pub next_chunk_id: u32,
+    pub timestamp: f32,
+    pub entities: Vec<KeyframeEntity>,
+}
+
+impl Keyframe {
+    pub fn parse(chunk: &Chunk) -> ParseResult<Option<Self>> {
+        if chunk.type_ != KEYFRAME_CHUNK_TYPE {
+            return Ok(None);
+        }
+
+        let Some(payload) = &chunk.payload else {
+            return Ok(None);
+        };
+        let mut block_parser = BlockParser::new(payload);
+
+        // keyframe blocks use the entity net id as param
+        let mut entities: BTreeMap<u32, Vec<Block<'static>>> = BTreeMap::new();
+        let mut timestamp = None;
+        while let Some(block) = block_parser
+            .next_block()
+            .map_err(|e| e.in_chunk(chunk.id))?
+        {
+            timestamp.get_or_insert(block.timestamp);
+            entities
+                .entry(block.param)
+                .or_default()
+                .push(block.into_owned());
+        }
+
+        Ok(Some(Keyframe {
+            id: chunk.id,
+            next_chunk_id: chunk.id_2,
+            timestamp: timestamp.unwrap_or(0.0),
+            entities: entities
+                .into_iter()
+                .map(|(id, blocks)| KeyframeEntity { id, blocks })
+                .collect(),
+        }))
+    }
+
+    pub fn blocks_with_id(&self, id: u16) -> impl Iterator<Item = &Block<'static>> {
+        self.entities
+            .iter()
+            .flat_map(|entity| entity.blocks.iter())
+            .filter(move |block| block.packet_id == id)
+    }
+}
@@ -0,0 +1,465 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use blowfish::{
+    cipher::{generic_array::GenericArray, BlockDecrypt, KeyInit},
+    Blowfish,
+};
+use flate2::read::GzDecoder;
+
+use crate::parser::chunk::ChunkHeader;
+use crate::parser::error::{ParseError, ParseResult};
+
+/*
+ROFL v1 layout:
+    0x000   magic "RIOT\0\0"
+    0x006   signature (0x100 bytes)
+    0x106   u16 header length
+    0x108   u32 file length
+    0x10C   u32 metadata offset
+    0x110   u32 metadata length
+    0x114   u32 payload header offset
+    0x118   u32 payload header length
+    0x11C   u32 payload offset
+
+payload header:
+    u64 game id
+    u32 game length
+    u32 keyframe count
+    u32 chunk count
+    u32 end startup chunk id
+    u32 start game chunk id
+    u32 keyframe interval
+    u16 encryption key length
+    encryption key (base64)
+
+payload:
+    (chunk count + keyframe count) * chunk entries of 0x11 bytes
+    encrypted chunks, gzip compressed
+*/
+
+pub const LEGACY_FORMAT_VERSION: u16 = 0;
+
+pub const LEGACY_SIGNATURE_OFFSET: u64 = 0x6;
+pub const LEGACY_HEADER_OFFSET: u64 = 0x106;
+
+const CHUNK_ENTRY_SIZE: usize = 0x11;
+
+// 1KB of deflate data is plenty to inflate the first block header
+const PREFIX_ENCRYPTED_SIZE: u32 = 0x400;
+
+#[derive(Debug, Clone)]
+pub struct LegacyLayout {
+    pub header_len: u16,
+    pub file_len: u32,
+    pub payload_header_offset: u64,
+    pub payload_header_len: u32,
+}
+
+impl LegacyLayout {
+    // returns the layout plus the metadata and payload offsets, which are shared with ROFL2
+    pub fn read<R: Read + Seek>(reader: &mut R) -> ParseResult<(Self, u64, u32, u64)> {
+        let mut raw = [0u8; 0x1A];
+        reader.seek(SeekFrom::Start(LEGACY_HEADER_OFFSET))?;
+        reader.read_exact(&mut raw)?;
+
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(raw[offset..offset + 4].try_into().unwrap());
+
+        let layout = LegacyLayout {
+            header_len: u16::from_le_bytes([raw[0], raw[1]]),
+            file_len: u32_at(0x2),
+            payload_header_offset: u32_at(0xE) as u64,
+            payload_header_len: u32_at(0x12),
+        };
+
+        let metadata_offset = u32_at(0x6) as u64;
+        let metadata_len = u32_at(0xA);
+        let payload_offset = u32_at(0x16) as u64;
+
+        Ok((layout, metadata_offset, metadata_len, payload_offset))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LegacyPayloadHeader {
+    pub game_id: u64,
+    pub game_len: u32,
+    pub keyframe_count: u32,
+    pub chunk_count: u32,
+    pub end_startup_chunk_id: u32,
+    pub start_game_chunk_id: u32,
+    pub keyframe_interval: u32,
+    pub encryption_key: String,
+}
+
+impl LegacyPayloadHeader {
+    pub fn read<R: Read + Seek>(reader: &mut R, layout: &LegacyLayout) -> ParseResult<Self> {
+        if layout.payload_header_offset + layout.payload_header_len as u64 > layout.file_len as u64
+        {
+            return Err(ParseError::invalid(
+                "legacy payload header length",
+                format!(
+                    "{} (file is {} bytes)",
+                    layout.payload_header_len, layout.file_len
+                ),
+            )
+            .at(LEGACY_HEADER_OFFSET + 0x12));
+        }
+
+        let mut raw = vec![0u8; layout.payload_header_len as usize];
+        reader.seek(SeekFrom::Start(layout.payload_header_offset))?;
+        reader.read_exact(&mut raw)?;
+
+        if raw.len() < 0x22 {
+            return Err(ParseError::eof("legacy payload header").at(layout.payload_header_offset));
+        }
+
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(raw[offset..offset + 4].try_into().unwrap());
+
+        let key_len = u16::from_le_bytes([raw[0x20], raw[0x21]]) as usize;
+        let raw_key = raw.get(0x22..0x22 + key_len).ok_or_else(|| {
+            ParseError::eof("legacy encryption key").at(layout.payload_header_offset + 0x22)
+        })?;
+
+        Ok(LegacyPayloadHeader {
+            game_id: u64::from_le_bytes(raw[0..8].try_into().unwrap()),
+            game_len: u32_at(0x8),
+            keyframe_count: u32_at(0xC),
+            chunk_count: u32_at(0x10),
+            end_startup_chunk_id: u32_at(0x14),
+            start_game_chunk_id: u32_at(0x18),
+            keyframe_interval: u32_at(0x1C),
+            encryption_key: String::from_utf8(raw_key.to_vec()).map_err(|e| {
+                ParseError::invalid("legacy encryption key", e)
+                    .at(layout.payload_header_offset + 0x22)
+            })?,
+        })
+    }
+
+    // the chunk key is stored encrypted with the game id
+    pub fn chunk_key(&self) -> ParseResult<Vec<u8>> {
+        let encrypted_key = BASE64
+            .decode(&self.encryption_key)
+            .map_err(|e| ParseError::invalid("base64 encryption key", e))?;
+        blowfish_decrypt(self.game_id.to_string().as_bytes(), encrypted_key)
+    }
+}
+
+pub struct LegacyChunkTable {
+    // sorted by payload offset, so chunks and keyframes come out in file order like ROFL2
+    entries: Vec<ChunkHeader>,
+    next_entry: usize,
+
+    chunk_key: Vec<u8>,
+}
+
+impl LegacyChunkTable {
+    pub fn read<R: Read + Seek>(
+        reader: &mut R,
+        layout: &LegacyLayout,
+        payload_header: &LegacyPayloadHeader,
+        payload_offset: u64,
+    ) -> ParseResult<Self> {
+        // both counts come straight from the file, a corrupted one must not allocate gigabytes
+        let table_len = payload_header
+            .chunk_count
+            .checked_add(payload_header.keyframe_count)
+            .and_then(|entry_count| (entry_count as u64).checked_mul(CHUNK_ENTRY_SIZE as u64))
+            .filter(|&table_len| payload_offset + table_len <= layout.file_len as u64)
+            .ok_or_else(|| {
+                ParseError::invalid(
+                    "legacy chunk and keyframe counts",
+                    format!(
+                        "{} chunks and {} keyframes (file is {} bytes)",
+                        payload_header.chunk_count, payload_header.keyframe_count, layout.file_len
+                    ),
+                )
+                .at(layout.payload_header_offset + 0xC)
+            })?;
+
+        let mut raw = vec![0u8; table_len as usize];
+        reader.seek(SeekFrom::Start(payload_offset))?;
+        reader.read_exact(&mut raw)?;
+
+        let data_offset = payload_offset + raw.len() as u64;
+
+        let mut entries: Vec<ChunkHeader> = raw
+            .chunks_exact(CHUNK_ENTRY_SIZE)
+            .map(|entry| ChunkHeader {
+                id: u32::from_le_bytes(entry[0..4].try_into().unwrap()),
+                type_: entry[4],
+                id_2: u32::from_le_bytes(entry[9..13].try_into().unwrap()),
+                // only known once the chunk is decrypted
+                uncompressed_len: 0,
+                compressed_len: u32::from_le_bytes(entry[5..9].try_into().unwrap()),
+                payload_offset: data_offset
+                    + u32::from_le_bytes(entry[13..17].try_into().unwrap()) as u64,
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.payload_offset);
+
+        Ok(LegacyChunkTable {
+            entries,
+            next_entry: 0,
+            chunk_key: payload_header.chunk_key()?,
+        })
+    }
+
+    pub fn next_header(&mut self) -> Option<ChunkHeader> {
+        let header = self.entries.get(self.next_entry)?.clone();
+        self.next_entry += 1;
+        Some(header)
+    }
+
+    pub fn rewind(&mut self) {
+        self.next_entry = 0;
+    }
+
+    pub fn seek_to(&mut self, header: &ChunkHeader) {
+        self.next_entry = self
+            .entries
+            .partition_point(|entry| entry.payload_offset < header.payload_offset);
+    }
+
+    pub fn chunk_key(&self) -> &[u8] {
+        &self.chunk_key
+    }
+
+    // decrypts just enough blocks to inflate the first `len` bytes of the chunk
+    pub fn read_payload_prefix<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        header: &ChunkHeader,
+        len: usize,
+    ) -> ParseResult<Vec<u8>> {
+        let encrypted_len = header.compressed_len.min(PREFIX_ENCRYPTED_SIZE) & !7;
+        let mut compressed_prefix = Self::read_encrypted(reader, header, encrypted_len)?;
+
+        let cipher = blowfish_cipher(&self.chunk_key)?;
+        for block in compressed_prefix.chunks_exact_mut(8) {
+            cipher.decrypt_block(GenericArray::from_mut_slice(block));
+        }
+
+        // the deflate stream is cut short on purpose, so errors past `len` don't matter
+        let mut prefix = Vec::with_capacity(len);
+        let _ = GzDecoder::new(compressed_prefix.as_slice())
+            .take(len as u64)
+            .read_to_end(&mut prefix);
+
+        Ok(prefix)
+    }
+
+    fn read_encrypted<R: Read + Seek>(
+        reader: &mut R,
+        header: &ChunkHeader,
+        len: u32,
+    ) -> ParseResult<Vec<u8>> {
+        let mut encrypted = vec![0u8; len as usize];
+        reader.seek(SeekFrom::Start(header.payload_offset))?;
+        reader.read_exact(&mut encrypted).map_err(|e| {
+            ParseError::from(e)
+                .at(header.payload_offset)
+                .in_chunk(header.id)
+        })?;
+        Ok(encrypted)
+    }
+}
+
+// decrypts and inflates a whole chunk, as read from `header.payload_offset`
+pub fn decode_payload(
+    chunk_key: &[u8],
+    header: &ChunkHeader,
+    encrypted_payload: Vec<u8>,
+) -> ParseResult<Vec<u8>> {
+    let compressed_payload = blowfish_decrypt(chunk_key, encrypted_payload)
+        .map_err(|e| e.at(header.payload_offset).in_chunk(header.id))?;
+
+    let mut payload = Vec::new();
+    GzDecoder::new(compressed_payload.as_slice())
+        .read_to_end(&mut payload)
+        .map_err(|e| {
+            ParseError::decompress(e)
+                .at(header.payload_offset)
+                .in_chunk(header.id)
+        })?;
+
+    Ok(payload)
+}
+
+fn blowfish_cipher(key: &[u8]) -> ParseResult<Blowfish> {
+    Blowfish::new_from_slice(key).map_err(|_| ParseError::invalid("blowfish key length", key.len()))
+}
+
+// blowfish ECB with PKCS#5 padding
+fn blowfish_decrypt(key: &[u8], mut data: Vec<u8>) -> ParseResult<Vec<u8>> {
+    if data.is_empty() || !data.len().is_multiple_of(8) {
+        return Err(ParseError::invalid(
+            "encrypted data length (multiple of 8)",
+            data.len(),
+        ));
+    }
+
+    let cipher = blowfish_cipher(key)?;
+
+    for block in data.chunks_exact_mut(8) {
+        cipher.decrypt_block(GenericArray::from_mut_slice(block));
+    }
+
+    let padding = *data.last().unwrap() as usize;
+    if padding == 0 || padding > 8 {
+        return Err(ParseError::invalid("padding after decryption", padding));
+    }
+    data.truncate(data.len() - padding);
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use blowfish::cipher::BlockEncrypt;
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+    use crate::parser::block::{Block, BlockParser, BlockWriter};
+    use crate::parser::chunk::ChunkParser;
+    use crate::parser::header::RoflHeader;
+    use crate::parser::writer::tests::block;
+
+    const GAME_ID: u64 = 3141592653;
+    const CHUNK_KEY: &[u8] = b"0123456789abcdef";
+
+    fn blowfish_encrypt(key: &[u8], mut data: Vec<u8>) -> Vec<u8> {
+        let padding = 8 - data.len() % 8;
+        data.resize(data.len() + padding, padding as u8);
+
+        let cipher = blowfish_cipher(key).unwrap();
+        for block in data.chunks_exact_mut(8) {
+            cipher.encrypt_block(GenericArray::from_mut_slice(block));
+        }
+        data
+    }
+
+    fn encrypt_chunk(blocks: &[Block]) -> Vec<u8> {
+        let mut block_writer = BlockWriter::default();
+        for block in blocks {
+            block_writer.write_block(block);
+        }
+
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&block_writer.finish()).unwrap();
+        blowfish_encrypt(CHUNK_KEY, gzip.finish().unwrap())
+    }
+
+    // a v1 replay holding `chunks`, as (id, type, id_2, blocks)
+    fn legacy_replay(chunks: &[(u32, u8, u32, Vec<Block>)]) -> Vec<u8> {
+        let metadata = br#"{"gameVersion": "7.14.194.5087"}"#;
+        let encryption_key = BASE64.encode(blowfish_encrypt(
+            GAME_ID.to_string().as_bytes(),
+            CHUNK_KEY.to_vec(),
+        ));
+
+        let mut payload_header = GAME_ID.to_le_bytes().to_vec();
+        for field in [1800000u32, 0, chunks.len() as u32, 0, 1, 60000] {
+            payload_header.extend_from_slice(&field.to_le_bytes());
+        }
+        payload_header.extend_from_slice(&(encryption_key.len() as u16).to_le_bytes());
+        payload_header.extend_from_slice(encryption_key.as_bytes());
+
+        let mut table = Vec::new();
+        let mut data = Vec::new();
+        for (id, type_, id_2, blocks) in chunks {
+            let encrypted = encrypt_chunk(blocks);
+            table.extend_from_slice(&id.to_le_bytes());
+            table.push(*type_);
+            table.extend_from_slice(&(encrypted.len() as u32).to_le_bytes());
+            table.extend_from_slice(&id_2.to_le_bytes());
+            table.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            data.extend_from_slice(&encrypted);
+        }
+
+        let metadata_offset = 0x120u32;
+        let payload_header_offset = metadata_offset + metadata.len() as u32;
+        let payload_offset = payload_header_offset + payload_header.len() as u32;
+        let file_len = payload_offset + (table.len() + data.len()) as u32;
+
+        let mut raw = b"RIOT\0\0".to_vec();
+        raw.resize(LEGACY_HEADER_OFFSET as usize, 0x55);
+        raw.extend_from_slice(&0x1Au16.to_le_bytes());
+        for field in [
+            file_len,
+            metadata_offset,
+            metadata.len() as u32,
+            payload_header_offset,
+            payload_header.len() as u32,
+            payload_offset,
+        ] {
+            raw.extend_from_slice(&field.to_le_bytes());
+        }
+        raw.extend_from_slice(metadata);
+        raw.extend_from_slice(&payload_header);
+        raw.extend_from_slice(&table);
+        raw.extend_from_slice(&data);
+        raw
+    }
+
+    fn chunks() -> Vec<(u32, u8, u32, Vec<Block<'static>>)> {
+        vec![
+            (1, 0x1, 0, vec![block(1.0, 1, 1), block(2.5, 2, 2)]),
+            (2, 0x1, 0, vec![block(31.0, 3, 3)]),
+        ]
+    }
+
+    fn timestamps(payload: &[u8]) -> Vec<f32> {
+        let mut block_parser = BlockParser::new(payload);
+        std::iter::from_fn(|| block_parser.next_block().unwrap())
+            .map(|block| block.timestamp)
+            .collect()
+    }
+
+    #[test]
+    fn decrypts_and_inflates_chunks() {
+        let mut parser = ChunkParser::new(Cursor::new(legacy_replay(&chunks()))).unwrap();
+
+        let first = parser.next_chunk().unwrap().unwrap();
+        assert_eq!((first.id, first.type_), (1, 0x1));
+        assert_eq!(timestamps(&first.payload.unwrap()), [1.0, 2.5]);
+
+        let second = parser.next_chunk().unwrap().unwrap();
+        assert_eq!(second.id, 2);
+        assert_eq!(timestamps(&second.payload.unwrap()), [31.0]);
+
+        assert!(parser.next_chunk().unwrap().is_none());
+    }
+
+    #[test]
+    fn start_time_only_inflates_the_prefix() {
+        let mut parser = ChunkParser::new(Cursor::new(legacy_replay(&chunks()))).unwrap();
+        let header = parser.next_chunk_header().unwrap().unwrap();
+        assert_eq!(parser.chunk_start_time(&header).unwrap(), Some(1.0));
+    }
+
+    #[test]
+    fn rejects_chunk_lengths_past_the_file() {
+        let mut raw = legacy_replay(&chunks());
+        let payload_offset = RoflHeader::read(&mut Cursor::new(&raw))
+            .unwrap()
+            .payload_offset as usize;
+        // compressed length of the first table entry
+        raw[payload_offset + 5..payload_offset + 9].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        let mut parser = ChunkParser::new(Cursor::new(raw.clone())).unwrap();
+        assert!(parser.next_chunk().is_err());
+
+        // recovery mode reports the chunk as damaged and keeps going
+        let mut parser = ChunkParser::new(Cursor::new(raw)).unwrap();
+        parser.recover();
+        let damaged = parser.next_chunk().unwrap().unwrap();
+        assert!(damaged.payload.is_none() && damaged.damage.is_some());
+        let second = parser.next_chunk().unwrap().unwrap();
+        assert_eq!(timestamps(&second.payload.unwrap()), [31.0]);
+    }
+}
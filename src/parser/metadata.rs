@@ -1,84 +1,436 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::path::Path;
+
+use hmac::{Hmac, Mac};
 use serde_json::*;
+use sha2::Sha256;
 
 use serde::{Deserialize, Serialize};
 
+use crate::parser::error::{ParseError, ParseResult};
+use crate::parser::header::RoflHeader;
+use crate::parser::stats::PlayerStats;
+use crate::parser::util::to_hex;
+use crate::parser::version::GameVersion;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Player {
+    // Riot id game name, or the summoner name on replays from before Riot ids
     pub name: String,
-    pub skin: String,
+    pub riot_id_game_name: Option<String>,
+    pub riot_id_tag_line: Option<String>,
+    pub puuid: Option<String>,
+    pub summoner_level: Option<u32>,
+    // internal champion name, "MonkeyKing" for Wukong
+    pub champion: String,
+    pub skin_index: Option<u32>,
     pub team: String,
     pub position: String,
+    pub position_source: PositionSource,
+    // only set for inferred positions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position_confidence: Option<f32>,
+    pub stats: PlayerStats,
+}
+
+// where `Player::position` comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PositionSource {
+    TeamPosition,
+    IndividualPosition,
+    // participant order, only right when it matches the role order
+    Index,
+    // early game movement, see roles::infer_roles
+    Inferred,
+}
+
+const POSITIONS: [&str; 5] = ["Top", "Jungle", "Mid", "Adc", "Support"];
+
+// TEAM_POSITION / INDIVIDUAL_POSITION values, empty or "Invalid" when the game couldn't tell
+fn position_name(value: &str) -> Option<&'static str> {
+    match value {
+        "TOP" => Some("Top"),
+        "JUNGLE" => Some("Jungle"),
+        "MIDDLE" | "MID" => Some("Mid"),
+        "BOTTOM" | "BOT" => Some("Adc"),
+        "UTILITY" | "SUPPORT" => Some("Support"),
+        _ => None,
+    }
+}
+
+fn player_position(stats: &PlayerStats, index: usize) -> (String, PositionSource) {
+    let fields = [
+        (&stats.team_position, PositionSource::TeamPosition),
+        (
+            &stats.individual_position,
+            PositionSource::IndividualPosition,
+        ),
+    ];
+
+    fields
+        .into_iter()
+        .find_map(|(value, source)| {
+            value
+                .as_deref()
+                .and_then(position_name)
+                .map(|position| (position.to_string(), source))
+        })
+        .unwrap_or_else(|| (POSITIONS[index % 5].to_string(), PositionSource::Index))
+}
+
+// remakes can only be voted in the first minutes, anything shorter that ended in a surrender is one
+const REMAKE_MAX_LEN: u64 = 4 * 60 * 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GameOutcome {
+    BlueWin,
+    RedWin,
+    Remake,
+    // no WIN field, or participants that disagree on the winner
+    Unknown,
+}
+
+fn game_outcome(players: &[Player], game_len: u64) -> GameOutcome {
+    let any = |flag: fn(&PlayerStats) -> Option<bool>| {
+        players
+            .iter()
+            .any(|player| flag(&player.stats) == Some(true))
+    };
+
+    let early_surrender = any(|stats| stats.game_ended_in_early_surrender)
+        || any(|stats| stats.team_early_surrendered);
+    let surrender = any(|stats| stats.game_ended_in_surrender);
+    if early_surrender || (surrender && game_len < REMAKE_MAX_LEN) {
+        return GameOutcome::Remake;
+    }
+
+    // every participant votes for a winner, a blue "Fail" is a red win
+    let (mut blue, mut red) = (0, 0);
+    for player in players {
+        match (player.team.as_str(), player.stats.win.as_deref()) {
+            ("Blue", Some("Win")) | ("Red", Some("Fail")) => blue += 1,
+            ("Red", Some("Win")) | ("Blue", Some("Fail")) => red += 1,
+            _ => {}
+        }
+    }
+
+    match (blue, red) {
+        (0, 0) => GameOutcome::Unknown,
+        (_, 0) => GameOutcome::BlueWin,
+        (0, _) => GameOutcome::RedWin,
+        _ => GameOutcome::Unknown,
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Metadata {
-    pub version: String,
+    pub version: GameVersion,
     pub game_len: u64,
-    pub winning_team: String,
+    pub outcome: GameOutcome,
     pub players: Vec<Player>,
 }
 
 impl Metadata {
-    pub fn parse(buffer: &[u8]) -> Self {
-        let version = String::from_utf8(buffer[16..20].to_vec()).unwrap();
-
-        let len = buffer.len();
+    // only reads the header and the json at the end of the file, never the chunks
+    pub fn from_path<P: AsRef<Path>>(path: P) -> ParseResult<Self> {
+        Self::read(&mut BufReader::new(File::open(path)?))
+    }
 
-        let raw_json_medata_size = &buffer[len - 4..];
-        let json_metadata_size = u32::from_le_bytes(raw_json_medata_size.try_into().unwrap());
+    pub fn read<R: Read + Seek>(reader: &mut R) -> ParseResult<Self> {
+        let header = RoflHeader::read(reader)?;
+        let version: GameVersion = header.game_version.parse()?;
+        let raw_medata_json = header.read_metadata(reader)?;
 
-        let raw_medata_json = &buffer[len - 4 - json_metadata_size as usize..len - 4];
-        let metadata_json = String::from_utf8(raw_medata_json.to_vec()).unwrap();
+        // errors below all point at the metadata json
+        let invalid = |expected: &'static str, found: &dyn std::fmt::Display| {
+            ParseError::invalid(expected, found).at(header.metadata_offset)
+        };
 
-        let json: Value = serde_json::from_str(&metadata_json).unwrap();
+        let json: Value =
+            serde_json::from_slice(&raw_medata_json).map_err(|e| invalid("metadata json", &e))?;
 
-        let game_len: u64 = json.get("gameLength").unwrap().as_i64().unwrap() as u64;
-        let stats_json: Value = serde_json::from_str(json["statsJson"].as_str().unwrap()).unwrap();
+        let game_len: u64 = json["gameLength"]
+            .as_u64()
+            .ok_or_else(|| invalid("gameLength", &json["gameLength"]))?;
+        let stats_json: Value = json["statsJson"]
+            .as_str()
+            .ok_or_else(|| invalid("statsJson", &json["statsJson"]))
+            .and_then(|s| serde_json::from_str(s).map_err(|e| invalid("statsJson", &e)))?;
 
-        let players: Vec<_> = stats_json
+        let entries = stats_json
             .as_array()
-            .unwrap()
+            .filter(|entries| !entries.is_empty())
+            .ok_or_else(|| invalid("statsJson player list", &stats_json))?;
+
+        let players = entries
             .iter()
             .enumerate()
             .map(|(i, player)| {
-                let name = player["NAME"].as_str().unwrap().to_string();
-                let skin = player["SKIN"].as_str().unwrap().to_string();
-                let team = match player["TEAM"].as_str().unwrap() {
-                    "100" => "Blue".to_string(),
-                    "200" => "Red".to_string(),
-                    _ => panic!("Invalid team"),
+                let stats: PlayerStats = serde_json::from_value(player.clone())
+                    .map_err(|e| invalid("player stats", &e))?;
+
+                // empty strings are what the client writes for unknown ids
+                let non_empty = |value: &Option<String>| value.clone().filter(|v| !v.is_empty());
+                let riot_id_game_name = non_empty(&stats.riot_id_game_name);
+                let riot_id_tag_line = non_empty(&stats.riot_id_tag_line);
+                let puuid = non_empty(&stats.puuid);
+
+                let name = riot_id_game_name
+                    .clone()
+                    .or_else(|| stats.name.clone())
+                    .ok_or_else(|| invalid("player NAME", &player["NAME"]))?;
+                let champion = stats
+                    .skin
+                    .clone()
+                    .ok_or_else(|| invalid("player SKIN", &player["SKIN"]))?;
+                let team = match stats.team.as_deref() {
+                    Some("100") => "Blue".to_string(),
+                    Some("200") => "Red".to_string(),
+                    _ => return Err(invalid("player TEAM", &player["TEAM"])),
                 };
 
-                let position = vec!["Top", "Jungle", "Mid", "Adc", "Support"][i % 5].to_string();
+                let (position, position_source) = player_position(&stats, i);
 
-                Player {
+                Ok(Player {
                     name,
-                    skin,
+                    riot_id_game_name,
+                    riot_id_tag_line,
+                    puuid,
+                    summoner_level: stats.summoner_level,
+                    champion,
+                    skin_index: stats.skin_id,
                     team,
                     position,
-                }
+                    position_source,
+                    position_confidence: None,
+                    stats,
+                })
             })
-            .collect();
-
-        let first_entry = stats_json.as_array().unwrap()[0].as_object().unwrap();
-        let winning_team = match (
-            first_entry.get("TEAM").unwrap().as_str().unwrap(),
-            first_entry.get("WIN").unwrap().as_str().unwrap(),
-        ) {
-            ("100", "Win") => "Blue".to_string(),
-            _ => "Red".to_string(),
-        };
+            .collect::<ParseResult<Vec<_>>>()?;
+
+        let outcome = game_outcome(&players, game_len);
 
-        Metadata {
+        Ok(Metadata {
             version,
             game_len,
-            winning_team,
+            outcome,
             players,
+        })
+    }
+
+    // None for entities that aren't players, or ids from a garbled packet
+    pub fn get_player_from_id(&self, id: u32, player_id_start: u32) -> Option<&Player> {
+        let index = id.checked_sub(player_id_start)?;
+        self.players.get(index as usize)
+    }
+}
+
+impl fmt::Display for Metadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = self.game_len / 1000;
+        let outcome = match self.outcome {
+            GameOutcome::BlueWin => "Blue won",
+            GameOutcome::RedWin => "Red won",
+            GameOutcome::Remake => "remake",
+            GameOutcome::Unknown => "unknown outcome",
+        };
+        writeln!(
+            f,
+            "version {}, {}:{:02}, {}",
+            self.version,
+            seconds / 60,
+            seconds % 60,
+            outcome
+        )?;
+
+        for player in &self.players {
+            let riot_id = match (&player.riot_id_game_name, &player.riot_id_tag_line) {
+                (Some(name), Some(tag)) => format!("{}#{}", name, tag),
+                _ => player.name.clone(),
+            };
+            writeln!(
+                f,
+                "  {:<4} {:<7} {:<14} {}",
+                player.team, player.position, player.champion, riot_id
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+// replaces names and Riot ids in the metadata json with pseudonyms keyed with `salt`, the
+// same player gets the same pseudonym in every replay anonymized with the same salt. without
+// the salt, a pseudonym can't be traced back by hashing known account ids
+pub fn anonymize_metadata(raw_metadata: &[u8], salt: &[u8]) -> ParseResult<Vec<u8>> {
+    let mut json: Value = serde_json::from_slice(raw_metadata)
+        .map_err(|e| ParseError::invalid("metadata json", e))?;
+
+    let mut stats_json: Value = json["statsJson"]
+        .as_str()
+        .ok_or_else(|| ParseError::invalid("statsJson", &json["statsJson"]))
+        .and_then(|s| serde_json::from_str(s).map_err(|e| ParseError::invalid("statsJson", e)))?;
+
+    let players = stats_json
+        .as_array_mut()
+        .ok_or_else(|| ParseError::invalid("statsJson player list", "not an array"))?;
+
+    for (i, player) in players.iter_mut().enumerate() {
+        // prefer the account id, names can be changed
+        let identity = ["PUUID", "RIOT_ID_GAME_NAME", "NAME"]
+            .iter()
+            .find_map(|key| player[*key].as_str().filter(|value| !value.is_empty()))
+            .map(|value| value.to_string())
+            .unwrap_or_else(|| format!("player {}", i));
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(salt)
+            .map_err(|e| ParseError::invalid("anonymization salt", e))?;
+        mac.update(identity.as_bytes());
+        let digest = mac.finalize().into_bytes();
+
+        let hash = u64::from_le_bytes(digest[0..8].try_into().unwrap());
+        let name = format!("Player{:08X}", hash as u32);
+        let pseudonyms = [
+            ("NAME", name.clone()),
+            ("RIOT_ID_GAME_NAME", name),
+            ("RIOT_ID_TAG_LINE", format!("{:04X}", (hash >> 48) as u16)),
+            ("PUUID", format!("{:016x}", hash)),
+            ("SUMMONER_ID", to_hex(&digest[8..16])),
+        ];
+
+        for (key, pseudonym) in pseudonyms {
+            if player.get(key).is_some() {
+                player[key] = Value::String(pseudonym);
+            }
+        }
+    }
+
+    json["statsJson"] = Value::String(stats_json.to_string());
+
+    Ok(json.to_string().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn player(team: &str, win: Option<&str>) -> Player {
+        Player {
+            name: String::new(),
+            riot_id_game_name: None,
+            riot_id_tag_line: None,
+            puuid: None,
+            summoner_level: None,
+            champion: String::new(),
+            skin_index: None,
+            team: team.to_string(),
+            position: String::new(),
+            position_source: PositionSource::Index,
+            position_confidence: None,
+            stats: PlayerStats {
+                win: win.map(str::to_string),
+                ..Default::default()
+            },
         }
     }
 
-    pub fn get_player_from_id(&self, id: u32, player_id_start: u32) -> Player {
-        let index = id - player_id_start;
-        self.players[index as usize].clone()
+    fn game(blue: Option<&str>, red: Option<&str>) -> Vec<Player> {
+        let mut players: Vec<Player> = (0..5).map(|_| player("Blue", blue)).collect();
+        players.extend((0..5).map(|_| player("Red", red)));
+        players
+    }
+
+    const GAME_LEN: u64 = 30 * 60 * 1000;
+
+    #[test]
+    fn every_participant_votes_for_the_winner() {
+        let outcome = |players: &[Player]| game_outcome(players, GAME_LEN);
+
+        assert_eq!(
+            outcome(&game(Some("Win"), Some("Fail"))),
+            GameOutcome::BlueWin
+        );
+        assert_eq!(
+            outcome(&game(Some("Fail"), Some("Win"))),
+            GameOutcome::RedWin
+        );
+        // one side is enough
+        assert_eq!(outcome(&game(None, Some("Fail"))), GameOutcome::BlueWin);
+        assert_eq!(outcome(&game(None, None)), GameOutcome::Unknown);
+        assert_eq!(
+            outcome(&game(Some("Win"), Some("Win"))),
+            GameOutcome::Unknown
+        );
+        assert_eq!(outcome(&[]), GameOutcome::Unknown);
+    }
+
+    #[test]
+    fn player_ids_out_of_range_are_not_players() {
+        let metadata = Metadata {
+            version: "15.4".parse().unwrap(),
+            game_len: GAME_LEN,
+            outcome: GameOutcome::Unknown,
+            players: game(None, None)[..6].to_vec(),
+        };
+
+        assert_eq!(
+            metadata
+                .get_player_from_id(0x4000_0005, 0x4000_0000)
+                .unwrap()
+                .team,
+            "Red"
+        );
+        assert!(metadata
+            .get_player_from_id(0x4000_0006, 0x4000_0000)
+            .is_none());
+        assert!(metadata
+            .get_player_from_id(0x3FFF_FFFF, 0x4000_0000)
+            .is_none());
+    }
+
+    #[test]
+    fn early_surrenders_are_remakes() {
+        let mut players = game(Some("Win"), Some("Fail"));
+        players[7].stats.game_ended_in_early_surrender = Some(true);
+        assert_eq!(game_outcome(&players, GAME_LEN), GameOutcome::Remake);
+
+        let mut players = game(Some("Fail"), Some("Win"));
+        players[0].stats.team_early_surrendered = Some(true);
+        assert_eq!(game_outcome(&players, GAME_LEN), GameOutcome::Remake);
+    }
+
+    #[test]
+    fn only_short_surrenders_are_remakes() {
+        let mut players = game(Some("Fail"), Some("Win"));
+        players[0].stats.game_ended_in_surrender = Some(true);
+
+        assert_eq!(
+            game_outcome(&players, REMAKE_MAX_LEN - 1),
+            GameOutcome::Remake
+        );
+        assert_eq!(game_outcome(&players, GAME_LEN), GameOutcome::RedWin);
+    }
+
+    #[test]
+    fn pseudonyms_depend_on_the_salt() {
+        let stats = r#"[{"NAME": "Faker", "PUUID": "abc-123", "RIOT_ID_TAG_LINE": "KR1"}]"#;
+        let raw = serde_json::to_vec(&json!({ "statsJson": stats })).unwrap();
+
+        let pseudonyms = |salt: &[u8]| {
+            let json: Value =
+                serde_json::from_slice(&anonymize_metadata(&raw, salt).unwrap()).unwrap();
+            serde_json::from_str::<Value>(json["statsJson"].as_str().unwrap()).unwrap()[0].clone()
+        };
+
+        let player = pseudonyms(b"secret");
+        assert!(player["NAME"].as_str().unwrap().starts_with("Player"));
+        assert_ne!(player["PUUID"], "abc-123");
+        assert_ne!(player["RIOT_ID_TAG_LINE"], "KR1");
+        assert_eq!(pseudonyms(b"secret"), player);
+        assert_ne!(pseudonyms(b"other secret")["PUUID"], player["PUUID"]);
     }
 }
@@ -1,5 +1,18 @@
 pub mod block;
 pub mod chunk;
+pub mod demux;
+pub mod dump;
+pub mod error;
+pub mod header;
+pub mod index;
+pub mod inspect;
+pub mod keyframe;
+pub mod legacy;
 pub mod metadata;
 pub mod parser;
+pub mod recovery;
+pub mod rewrite;
+pub mod stats;
 pub mod util;
+pub mod version;
+pub mod writer;
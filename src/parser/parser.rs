@@ -1,28 +1,370 @@
+use std::io::{Read, Seek};
+
 use crate::parser::block::*;
 use crate::parser::chunk::*;
+use crate::parser::error::{ParseError, ParseResult};
+use crate::parser::index::*;
+use crate::parser::keyframe::*;
+use crate::parser::recovery::RecoveryReport;
 
 use rayon::prelude::*;
 
-pub fn get_blocks(buffer: Vec<u8>) -> Vec<Block> {
+// (timestamp, payload)
+pub type BlockPayload = (f32, Vec<u8>);
+
+// chunks decoded at once in parallel mode, bounds the memory held by decompressed chunks
+const PARALLEL_WINDOW: usize = 64;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ParseOptions {
+    pub range: Option<(f32, f32)>,
+    // skip damaged chunks instead of failing, see RecoveryReport
+    pub recover: bool,
+    // decompress and parse chunks on the rayon pool
+    pub parallel: bool,
+}
+
+fn new_chunk_parser<R: Read + Seek>(reader: R, recover: bool) -> ParseResult<ChunkParser<R>> {
+    let mut chunk_parser = ChunkParser::new(reader)?;
+    if recover {
+        chunk_parser.recover();
+    }
+
+    Ok(chunk_parser)
+}
+
+// next chunk holding game blocks (or a keyframe, when asked for), None once past the end of
+// the range
+fn next_block_chunk<R: Read + Seek>(
+    chunk_parser: &mut ChunkParser<R>,
+    index: Option<&ReplayIndex>,
+    end: f32,
+    keyframes: bool,
+) -> ParseResult<Option<ChunkHeader>> {
+    while let Some(header) = chunk_parser.next_chunk_header()? {
+        if header.type_ == KEYFRAME_CHUNK_TYPE && !keyframes {
+            continue;
+        }
+
+        if index.is_some_and(|index| index.is_past(&header, end)) {
+            return Ok(None);
+        }
+
+        return Ok(Some(header));
+    }
+
+    Ok(None)
+}
+
+// keeps the order of `items`, whether it runs on the rayon pool or not
+fn map_chunks<T, U, F>(items: Vec<T>, parallel: bool, f: F) -> ParseResult<Vec<U>>
+where
+    T: Send,
+    U: Send,
+    F: Fn(T) -> ParseResult<U> + Sync + Send,
+{
+    if parallel {
+        items.into_par_iter().map(f).collect()
+    } else {
+        items.into_iter().map(f).collect()
+    }
+}
+
+// every chunk gets a fresh BlockParser, relative timestamps and packet ids don't cross chunks.
+// keyframe chunks hold no game blocks, they are handled by parse_keyframe
+fn parse_chunk_blocks(
+    chunk: &Chunk,
+    recover: bool,
+) -> ParseResult<(Vec<Block<'_>>, Option<ParseError>)> {
     let mut blocks: Vec<Block> = Vec::new();
 
-    let mut chunk_parser = ChunkParser::new(buffer);
-    while let Some(chunk) = chunk_parser.next_chunk() {
-        if chunk.payload.is_some() && chunk.type_ != 0x2 {
-            let mut block_parser = BlockParser::new(chunk.payload.unwrap());
-            while let Some(block) = block_parser.next_block() {
-                blocks.push(block);
+    let Some(payload) = chunk
+        .payload
+        .as_ref()
+        .filter(|_| chunk.type_ != KEYFRAME_CHUNK_TYPE)
+    else {
+        return Ok((blocks, None));
+    };
+
+    let mut block_parser = BlockParser::new(payload);
+    if recover {
+        block_parser.recover();
+    }
+
+    while let Some(block) = block_parser
+        .next_block()
+        .map_err(|e| e.in_chunk(chunk.id))?
+    {
+        blocks.push(block);
+    }
+
+    let damage = block_parser.take_damage().map(|e| e.in_chunk(chunk.id));
+
+    Ok((blocks, damage))
+}
+
+fn emit_chunk_blocks<F: FnMut(&Block)>(
+    mut recovery: Option<&mut RecoveryReport>,
+    chunk: &Chunk,
+    blocks: Vec<Block>,
+    block_damage: Option<ParseError>,
+    range: (f32, f32),
+    f: &mut F,
+) {
+    if let (Some(recovery), Some(skipped)) = (recovery.as_deref_mut(), &chunk.skipped) {
+        recovery.record(skipped);
+    }
+
+    for block in blocks {
+        if let Some(recovery) = recovery.as_deref_mut() {
+            recovery.observe(block.timestamp);
+        }
+
+        if block.timestamp >= range.0 && block.timestamp <= range.1 {
+            f(&block);
+        }
+    }
+
+    // recorded after the blocks decoded before the damage, so the lost range starts after them
+    let damage = chunk.damage.as_ref().or(block_damage.as_ref());
+    if let (Some(recovery), Some(damage)) = (recovery, damage) {
+        recovery.record(damage);
+    }
+}
+
+fn parse_keyframe(chunk: &Chunk, recover: bool) -> ParseResult<Option<Keyframe>> {
+    // a partial snapshot would look like entities vanished, drop it
+    if chunk.damage.is_some() {
+        return Ok(None);
+    }
+
+    match Keyframe::parse(chunk) {
+        Err(_) if recover => Ok(None),
+        result => result,
+    }
+}
+
+// streams every block of the game to `f` in timestamp order, decompressing each chunk once.
+// with a range, only the chunks overlapping [start, end] are decompressed
+pub fn for_each_block<R: Read + Seek, F: FnMut(&Block)>(
+    reader: R,
+    options: &ParseOptions,
+    f: F,
+) -> ParseResult<RecoveryReport> {
+    walk_chunks(reader, options, None, f)
+}
+
+// for_each_block that also decodes the keyframe chunks it passes into `keyframes`, so
+// blocks and keyframes come out of the same single pass
+pub fn for_each_block_with_keyframes<R: Read + Seek, F: FnMut(&Block)>(
+    reader: R,
+    options: &ParseOptions,
+    keyframes: &mut Vec<Keyframe>,
+    f: F,
+) -> ParseResult<RecoveryReport> {
+    walk_chunks(reader, options, Some(keyframes), f)
+}
+
+fn walk_chunks<R: Read + Seek, F: FnMut(&Block)>(
+    reader: R,
+    options: &ParseOptions,
+    mut keyframes: Option<&mut Vec<Keyframe>>,
+    mut f: F,
+) -> ParseResult<RecoveryReport> {
+    let mut chunk_parser = new_chunk_parser(reader, options.recover)?;
+
+    let range = options.range.unwrap_or((0.0, f32::INFINITY));
+    let index = match options.range {
+        Some((start, _)) => {
+            let index = ReplayIndex::build(&mut chunk_parser)?;
+            chunk_parser.seek(&index, start);
+            Some(index)
+        }
+        None => None,
+    };
+
+    let window_len = if options.parallel { PARALLEL_WINDOW } else { 1 };
+    loop {
+        // reading stays sequential, only the decoding is spread over the pool
+        let mut raw_chunks: Vec<RawChunk> = Vec::with_capacity(window_len);
+        while raw_chunks.len() < window_len {
+            let Some(header) = next_block_chunk(
+                &mut chunk_parser,
+                index.as_ref(),
+                range.1,
+                keyframes.is_some(),
+            )?
+            else {
+                break;
+            };
+            raw_chunks.push(chunk_parser.read_raw_chunk(&header)?);
+        }
+
+        if raw_chunks.is_empty() {
+            break;
+        }
+
+        let chunks = map_chunks(raw_chunks, options.parallel, RawChunk::decode)?;
+        let parsed = map_chunks(chunks.iter().collect(), options.parallel, |chunk| {
+            parse_chunk_blocks(chunk, options.recover)
+        })?;
+
+        for (chunk, (blocks, block_damage)) in chunks.iter().zip(parsed) {
+            if let (Some(keyframes), KEYFRAME_CHUNK_TYPE) = (keyframes.as_deref_mut(), chunk.type_)
+            {
+                keyframes.extend(parse_keyframe(chunk, options.recover)?);
             }
+
+            emit_chunk_blocks(
+                chunk_parser.recovery(),
+                chunk,
+                blocks,
+                block_damage,
+                range,
+                &mut f,
+            );
         }
     }
 
-    blocks
+    Ok(chunk_parser.into_recovery_report())
 }
 
-pub fn get_blocks_with_id(replay_file: &[u8], id: u16) -> Vec<(f32, Vec<u8>)> {
-    get_blocks(replay_file.to_vec())
-        .par_iter()
-        .filter(|b| b.packet_id == id)
-        .map(|b| (b.timestamp, b.payload.clone()))
-        .collect()
+pub fn get_keyframes<R: Read + Seek>(reader: R, recover: bool) -> ParseResult<Vec<Keyframe>> {
+    let mut keyframes: Vec<Keyframe> = Vec::new();
+
+    // only keyframe chunks are decompressed, the headers tell them apart
+    let mut chunk_parser = new_chunk_parser(reader, recover)?;
+    while let Some(header) = chunk_parser.next_chunk_header()? {
+        if header.type_ != KEYFRAME_CHUNK_TYPE {
+            continue;
+        }
+
+        let chunk = chunk_parser.read_chunk(&header)?;
+        keyframes.extend(parse_keyframe(&chunk, recover)?);
+    }
+
+    Ok(keyframes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::parser::index::tests::{uneven_replay, UNEVEN_CHUNKS};
+    use crate::parser::writer::tests::{block, header, METADATA};
+    use crate::parser::writer::RoflWriter;
+
+    fn timestamps(raw: Vec<u8>, options: &ParseOptions) -> Vec<f32> {
+        let mut timestamps = Vec::new();
+        for_each_block(Cursor::new(raw), options, |block| {
+            timestamps.push(block.timestamp)
+        })
+        .unwrap();
+        timestamps
+    }
+
+    #[test]
+    fn range_keeps_blocks_of_chunks_with_misestimated_starts() {
+        let options = ParseOptions {
+            range: Some((92.0, 119.0)),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            timestamps(uneven_replay(&UNEVEN_CHUNKS), &options),
+            [93.0, 95.0, 105.0, 115.0, 118.0]
+        );
+    }
+
+    #[test]
+    fn range_covering_everything_matches_no_range() {
+        let raw = uneven_replay(&UNEVEN_CHUNKS);
+        let everything = ParseOptions {
+            range: Some((0.0, 1000.0)),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            timestamps(raw.clone(), &everything),
+            timestamps(raw, &ParseOptions::default())
+        );
+    }
+
+    // (timestamp, packet id, param, payload) of every block
+    fn blocks(raw: Vec<u8>, options: &ParseOptions) -> Vec<(f32, u16, u32, Vec<u8>)> {
+        let mut blocks = Vec::new();
+        for_each_block(Cursor::new(raw), options, |block| {
+            blocks.push((
+                block.timestamp,
+                block.packet_id,
+                block.param,
+                block.payload.to_vec(),
+            ))
+        })
+        .unwrap();
+        blocks
+    }
+
+    // more chunks than PARALLEL_WINDOW. each one starts with a block relative to "the previous
+    // one": time +5ms, same packet id, param +7, which only decodes right from a fresh state
+    fn many_chunks() -> Vec<u8> {
+        let mut writer = RoflWriter::new(Vec::new(), &header("15.4.658.1234")).unwrap();
+        for i in 0..PARALLEL_WINDOW as u32 + 6 {
+            let mut payload = vec![0xF0, 5, 1, 7, i as u8];
+
+            let mut block_writer = BlockWriter::default();
+            // far enough from the writer's zeroed state to be written in full
+            block_writer.write_block(&block(
+                i as f32 * 30.0 + 10.0,
+                0x100 + i as u16,
+                0x4000_0000 + i,
+            ));
+            block_writer.write_block(&block(
+                i as f32 * 30.0 + 11.0,
+                0x100 + i as u16,
+                0x4000_0001 + i,
+            ));
+            payload.extend(block_writer.finish());
+
+            writer.write_chunk_payload(i + 1, 0x1, 0, &payload).unwrap();
+        }
+        writer.finish(&[], METADATA).unwrap()
+    }
+
+    #[test]
+    fn parallel_matches_sequential() {
+        let raw = many_chunks();
+        let sequential = blocks(raw.clone(), &ParseOptions::default());
+        let parallel = blocks(
+            raw,
+            &ParseOptions {
+                parallel: true,
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(sequential.len(), (PARALLEL_WINDOW + 6) * 3);
+        assert_eq!(parallel, sequential);
+    }
+
+    #[test]
+    fn block_state_starts_fresh_in_every_chunk() {
+        let parallel = ParseOptions {
+            parallel: true,
+            ..Default::default()
+        };
+
+        for (i, chunk) in blocks(many_chunks(), &parallel).chunks(3).enumerate() {
+            let i = i as u32;
+            assert!((chunk[0].0 - 0.005).abs() < 1e-6);
+            assert_eq!((chunk[0].1, chunk[0].2), (0, 7));
+            assert_eq!(chunk[0].3, [i as u8]);
+            assert_eq!(
+                (chunk[1].0, chunk[1].1, chunk[1].2),
+                (i as f32 * 30.0 + 10.0, 0x100 + i as u16, 0x4000_0000 + i)
+            );
+            assert_eq!(chunk[2].2, 0x4000_0001 + i);
+        }
+    }
 }
@@ -0,0 +1,89 @@
+use serde::Serialize;
+
+use crate::parser::error::ParseError;
+
+// span of the game that couldn't be decoded, in seconds
+#[derive(Debug, Clone, Serialize)]
+pub struct LostRange {
+    pub start: f32,
+    // None when nothing could be decoded after the damage
+    pub end: Option<f32>,
+    pub offset: Option<u64>,
+    pub chunk_id: Option<u32>,
+    pub reason: String,
+}
+
+// damage skipped over by a parser in recovery mode
+#[derive(Debug, Default)]
+pub struct RecoveryReport {
+    pub lost: Vec<LostRange>,
+
+    last_timestamp: f32,
+}
+
+impl RecoveryReport {
+    pub fn record(&mut self, error: &ParseError) {
+        // several failures in a row before anything decodes again are the same hole
+        if self.lost.last().is_some_and(|range| range.end.is_none()) {
+            return;
+        }
+
+        self.lost.push(LostRange {
+            start: self.last_timestamp,
+            end: None,
+            offset: error.offset(),
+            chunk_id: error.chunk_id(),
+            reason: error.to_string(),
+        });
+    }
+
+    // called for every decoded block, closes the pending lost range
+    pub fn observe(&mut self, timestamp: f32) {
+        if let Some(range) = self.lost.last_mut().filter(|range| range.end.is_none()) {
+            range.end = Some(timestamp);
+        }
+
+        self.last_timestamp = timestamp;
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.lost.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn damage_opens_a_range_until_the_next_block() {
+        let mut report = RecoveryReport::default();
+        assert!(report.is_clean());
+
+        report.observe(10.0);
+        report.record(&ParseError::eof("chunk payload").at(0x40).in_chunk(3));
+        // more failures before anything decodes again are the same hole
+        report.record(&ParseError::invalid("zstd frame magic", "garbage"));
+        report.observe(70.0);
+        report.observe(71.0);
+
+        assert_eq!(report.lost.len(), 1);
+        let range = &report.lost[0];
+        assert_eq!((range.start, range.end), (10.0, Some(70.0)));
+        assert_eq!((range.offset, range.chunk_id), (Some(0x40), Some(3)));
+    }
+
+    #[test]
+    fn damage_at_the_end_stays_open() {
+        let mut report = RecoveryReport::default();
+        report.observe(10.0);
+        report.record(&ParseError::eof("chunk payload"));
+        report.observe(20.0);
+        report.record(&ParseError::eof("chunk payload"));
+
+        assert_eq!(report.lost.len(), 2);
+        assert_eq!(report.lost[1].start, 20.0);
+        assert_eq!(report.lost[1].end, None);
+        assert!(!report.is_clean());
+    }
+}
@@ -0,0 +1,83 @@
+use std::io::{Read, Seek, Write};
+
+use crate::parser::block::*;
+use crate::parser::chunk::*;
+use crate::parser::error::ParseResult;
+use crate::parser::header::RoflHeader;
+use crate::parser::keyframe::KEYFRAME_CHUNK_TYPE;
+use crate::parser::metadata::anonymize_metadata;
+use crate::parser::writer::RoflWriter;
+
+#[derive(Debug, Clone, Default)]
+pub struct RewriteOptions {
+    pub range: Option<(f32, f32)>,
+    pub drop_packet_ids: Vec<u16>,
+    // salt of the pseudonyms, names are kept when None
+    pub anonymize: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Default)]
+pub struct RewriteSummary {
+    pub chunks: usize,
+    pub keyframes: usize,
+    pub blocks: usize,
+    pub dropped_blocks: usize,
+}
+
+// re-encodes every chunk, keeping only the blocks that pass the options
+pub fn rewrite<R: Read + Seek, W: Write>(
+    mut reader: R,
+    writer: W,
+    options: &RewriteOptions,
+) -> ParseResult<RewriteSummary> {
+    let header = RoflHeader::read(&mut reader)?;
+
+    let mut metadata = header.read_metadata(&mut reader)?;
+    if let Some(salt) = &options.anonymize {
+        metadata = anonymize_metadata(&metadata, salt).map_err(|e| e.at(header.metadata_offset))?;
+    }
+
+    let (start, end) = options.range.unwrap_or((0.0, f32::INFINITY));
+
+    let mut summary = RewriteSummary::default();
+    let mut rofl_writer = RoflWriter::new(writer, &header)?;
+
+    let mut chunk_parser = ChunkParser::new(reader)?;
+    while let Some(chunk) = chunk_parser.next_chunk()? {
+        let mut blocks: Vec<Block> = Vec::new();
+        if let Some(payload) = &chunk.payload {
+            let mut block_parser = BlockParser::new(payload);
+            while let Some(block) = block_parser
+                .next_block()
+                .map_err(|e| e.in_chunk(chunk.id))?
+            {
+                if block.timestamp >= start
+                    && block.timestamp <= end
+                    && !options.drop_packet_ids.contains(&block.packet_id)
+                {
+                    blocks.push(block);
+                } else {
+                    summary.dropped_blocks += 1;
+                }
+            }
+        }
+
+        // chunks outside the range disappear entirely
+        if blocks.is_empty() {
+            continue;
+        }
+
+        rofl_writer.write_chunk(chunk.id, chunk.type_, chunk.id_2, &blocks)?;
+
+        if chunk.type_ == KEYFRAME_CHUNK_TYPE {
+            summary.keyframes += 1;
+        } else {
+            summary.chunks += 1;
+        }
+        summary.blocks += blocks.len();
+    }
+
+    rofl_writer.finish(&header.signature, &metadata)?;
+
+    Ok(summary)
+}
@@ -0,0 +1,317 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+// statsJson stores every value as a string ("KILLS": "5"), empty when not applicable.
+// values that don't fit the field (negative, fractional, ...) are read as missing instead of
+// failing the whole metadata
+fn stat<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+{
+    Ok(match Value::deserialize(deserializer)? {
+        Value::String(s) => s.trim().parse().ok(),
+        Value::Number(n) => n.to_string().parse().ok(),
+        Value::Bool(b) => (b as u8).to_string().parse().ok(),
+        _ => None,
+    })
+}
+
+// "0" / "1" flags
+fn flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<bool>, D::Error> {
+    Ok(stat::<D, u8>(deserializer)?.map(|value| value != 0))
+}
+
+// one statsJson entry. keys are read in SCREAMING_SNAKE_CASE and written in snake_case,
+// keys we don't know yet are kept as is in `extra`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "SCREAMING_SNAKE_CASE"))]
+pub struct PlayerStats {
+    // already part of Player
+    #[serde(default, skip_serializing)]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub skin: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub team: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub puuid: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub riot_id_game_name: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub riot_id_tag_line: Option<String>,
+    #[serde(default, skip_serializing, deserialize_with = "stat")]
+    pub summoner_level: Option<u32>,
+    #[serde(
+        default,
+        skip_serializing,
+        alias = "SKIN_INDEX",
+        deserialize_with = "stat"
+    )]
+    pub skin_id: Option<u32>,
+
+    #[serde(default, deserialize_with = "stat")]
+    pub id: Option<u32>,
+    #[serde(default)]
+    pub summoner_id: Option<String>,
+    #[serde(default)]
+    pub win: Option<String>,
+    #[serde(default)]
+    pub team_position: Option<String>,
+    #[serde(default)]
+    pub individual_position: Option<String>,
+
+    #[serde(default, deserialize_with = "stat")]
+    pub level: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub exp: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub time_played: Option<u32>,
+
+    // KDA
+    #[serde(default, deserialize_with = "stat")]
+    pub champions_killed: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub num_deaths: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub assists: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub largest_killing_spree: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub largest_multi_kill: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub killing_sprees: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub double_kills: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub triple_kills: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub quadra_kills: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub penta_kills: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub unreal_kills: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub bounty_level: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub longest_time_spent_living: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub total_time_spent_dead: Option<u32>,
+
+    // farm and gold
+    #[serde(default, deserialize_with = "stat")]
+    pub minions_killed: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub neutral_minions_killed: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub neutral_minions_killed_your_jungle: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub neutral_minions_killed_enemy_jungle: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub gold_earned: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub gold_spent: Option<u32>,
+
+    // items, ITEM6 is the trinket
+    #[serde(default, deserialize_with = "stat")]
+    pub item0: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub item1: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub item2: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub item3: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub item4: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub item5: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub item6: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub items_purchased: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub consumables_purchased: Option<u32>,
+
+    // vision
+    #[serde(default, deserialize_with = "stat")]
+    pub vision_score: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub ward_placed: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub ward_killed: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub ward_placed_detector: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub sight_wards_bought_in_game: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub vision_wards_bought_in_game: Option<u32>,
+
+    // damage dealt
+    #[serde(default, deserialize_with = "stat")]
+    pub total_damage_dealt: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub total_damage_dealt_to_champions: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub physical_damage_dealt_player: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub physical_damage_dealt_to_champions: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub magic_damage_dealt_player: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub magic_damage_dealt_to_champions: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub true_damage_dealt_player: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub true_damage_dealt_to_champions: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub total_damage_dealt_to_buildings: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub total_damage_dealt_to_turrets: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub total_damage_dealt_to_objectives: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub largest_critical_strike: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub largest_ability_damage: Option<u32>,
+
+    // damage taken and healing
+    #[serde(default, deserialize_with = "stat")]
+    pub total_damage_taken: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub physical_damage_taken: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub magic_damage_taken: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub true_damage_taken: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub total_damage_self_mitigated: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub total_damage_shielded_on_teammates: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub total_heal: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub total_heal_on_teammates: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub time_ccing_others: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub total_time_crowd_control_dealt: Option<u32>,
+
+    // objectives
+    #[serde(default, deserialize_with = "stat")]
+    pub turrets_killed: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub turret_takedowns: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub barracks_killed: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub barracks_takedowns: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub hq_killed: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub dragon_kills: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub baron_kills: Option<u32>,
+
+    // abilities and runes
+    #[serde(default, deserialize_with = "stat")]
+    pub spell1_cast: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub spell2_cast: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub spell3_cast: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub spell4_cast: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub summon_spell1_cast: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub summon_spell2_cast: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub keystone_id: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub perk_primary_style: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub perk_sub_style: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub perk0: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub perk1: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub perk2: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub perk3: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub perk4: Option<u32>,
+    #[serde(default, deserialize_with = "stat")]
+    pub perk5: Option<u32>,
+
+    // game end
+    #[serde(default, deserialize_with = "flag")]
+    pub game_ended_in_surrender: Option<bool>,
+    #[serde(default, deserialize_with = "flag")]
+    pub game_ended_in_early_surrender: Option<bool>,
+    #[serde(default, deserialize_with = "flag")]
+    pub team_early_surrendered: Option<bool>,
+    #[serde(default, deserialize_with = "flag")]
+    pub was_afk: Option<bool>,
+    #[serde(default, deserialize_with = "flag")]
+    pub was_leaver: Option<bool>,
+    #[serde(default, deserialize_with = "stat")]
+    pub time_spent_disconnected: Option<u32>,
+
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::parser::metadata::Metadata;
+    use crate::parser::writer::tests::header;
+    use crate::parser::writer::RoflWriter;
+
+    // a statsJson entry as found in replays, with a few values that don't fit a u32
+    const STATS: &str = r#"[{
+        "ID": "0", "NAME": "", "RIOT_ID_GAME_NAME": "Faker", "RIOT_ID_TAG_LINE": "KR1",
+        "SKIN": "Ahri", "TEAM": "100", "WIN": "Win", "LEVEL": "16", "EXP": "15873",
+        "CHAMPIONS_KILLED": "7", "NUM_DEATHS": "2", "ASSISTS": "11",
+        "GOLD_EARNED": "12504", "MINIONS_KILLED": "231", "NEUTRAL_MINIONS_KILLED": "",
+        "VISION_SCORE": "27.5", "TIME_SPENT_DISCONNECTED": "-1", "ITEM6": "3364",
+        "TOTAL_DAMAGE_SHIELDED_ON_TEAMMATES": "n/a", "PLAYER_SCORE_0": "0",
+        "GAME_ENDED_IN_SURRENDER": "0", "WAS_AFK": "maybe",
+        "TEAM_POSITION": "MIDDLE", "INDIVIDUAL_POSITION": "MIDDLE"
+    }]"#;
+
+    #[test]
+    fn odd_values_are_missing() {
+        let stats: Vec<PlayerStats> = serde_json::from_str(STATS).unwrap();
+        let stats = &stats[0];
+
+        assert_eq!(stats.champions_killed, Some(7));
+        assert_eq!(stats.gold_earned, Some(12504));
+        assert_eq!(stats.game_ended_in_surrender, Some(false));
+        assert_eq!(stats.neutral_minions_killed, None);
+        assert_eq!(stats.vision_score, None);
+        assert_eq!(stats.time_spent_disconnected, None);
+        assert_eq!(stats.total_damage_shielded_on_teammates, None);
+        assert_eq!(stats.was_afk, None);
+        assert_eq!(stats.extra["PLAYER_SCORE_0"], "0");
+    }
+
+    #[test]
+    fn odd_values_dont_fail_the_metadata() {
+        let metadata = serde_json::to_vec(&json!({ "gameLength": 1800000, "statsJson": STATS }));
+        let raw = RoflWriter::new(Vec::new(), &header("15.4.658.1234"))
+            .unwrap()
+            .finish(&[], &metadata.unwrap())
+            .unwrap();
+
+        let metadata = Metadata::read(&mut std::io::Cursor::new(raw)).unwrap();
+        assert_eq!(metadata.players.len(), 1);
+        assert_eq!(metadata.players[0].stats.num_deaths, Some(2));
+    }
+}
@@ -1,39 +1,38 @@
-use std::fs::File;
-use std::io::Read;
+use crate::parser::error::{ParseError, ParseResult};
 
-pub fn parse_f32(chunk: &mut impl Iterator<Item = u8>) -> Result<f32, ()> {
+pub fn parse_f32(chunk: &mut impl Iterator<Item = u8>) -> ParseResult<f32> {
     let bytes: [u8; 4] = chunk
         .take(4)
         .collect::<Vec<u8>>()
         .try_into()
-        .map_err(|_| ())?;
+        .map_err(|_| ParseError::eof("f32"))?;
     Ok(f32::from_le_bytes(bytes))
 }
 
-pub fn parse_u32(chunk: &mut impl Iterator<Item = u8>) -> Result<u32, ()> {
+pub fn parse_u32(chunk: &mut impl Iterator<Item = u8>) -> ParseResult<u32> {
     let bytes: [u8; 4] = chunk
         .take(4)
         .collect::<Vec<u8>>()
         .try_into()
-        .map_err(|_| ())?;
+        .map_err(|_| ParseError::eof("u32"))?;
     Ok(u32::from_le_bytes(bytes))
 }
 
-pub fn parse_u16(chunk: &mut impl Iterator<Item = u8>) -> Result<u16, ()> {
+pub fn parse_u16(chunk: &mut impl Iterator<Item = u8>) -> ParseResult<u16> {
     let bytes: [u8; 2] = chunk
         .take(2)
         .collect::<Vec<u8>>()
         .try_into()
-        .map_err(|_| ())?;
+        .map_err(|_| ParseError::eof("u16"))?;
     Ok(u16::from_le_bytes(bytes))
 }
 
-pub fn parse_u8(chunk: &mut impl Iterator<Item = u8>) -> Result<u8, ()> {
+pub fn parse_u8(chunk: &mut impl Iterator<Item = u8>) -> ParseResult<u8> {
     let bytes: [u8; 1] = chunk
         .take(1)
         .collect::<Vec<u8>>()
         .try_into()
-        .map_err(|_| ())?;
+        .map_err(|_| ParseError::eof("u8"))?;
     Ok(bytes[0])
 }
 
@@ -46,11 +45,8 @@ pub fn sign_extend(num: i16, bits: u32) -> i16 {
     (num << shift) >> shift
 }
 
-pub fn read_file(path: String) -> Vec<u8> {
-    let mut file = File::open(path).unwrap();
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).unwrap();
-    buffer
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
 }
 
 pub fn point_dist(x: (f32, f32), y: (f32, f32)) -> f32 {
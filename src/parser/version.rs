@@ -0,0 +1,128 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::parser::error::ParseError;
+
+// "15.4.658.1234": major.minor is the patch, the rest tells builds of a patch apart.
+// ordered by major, minor, then the build numbers, missing parts sort first
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct GameVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub build: Vec<u32>,
+}
+
+impl GameVersion {
+    // patch files are shared by every build of a patch
+    pub fn same_patch(&self, other: &GameVersion) -> bool {
+        self.major == other.major && self.minor == other.minor
+    }
+
+    // name of the patch file, 15.4.658.1234 -> 15-4
+    pub fn patch_name(&self) -> String {
+        format!("{}-{}", self.major, self.minor)
+    }
+}
+
+// accepts "15.4.658.1234" as well as patch file names like "15-4"
+impl FromStr for GameVersion {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s
+            .trim_end_matches(['.', '-'])
+            .split(['.', '-'])
+            .map(|part| part.parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| ParseError::invalid("game version", format!("{:?}", s)))?;
+
+        match parts.as_slice() {
+            [major, minor, build @ ..] => Ok(GameVersion {
+                major: *major,
+                minor: *minor,
+                build: build.to_vec(),
+            }),
+            _ => Err(ParseError::invalid(
+                "game version with at least major.minor",
+                format!("{:?}", s),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for GameVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)?;
+        for part in &self.build {
+            write!(f, ".{}", part)?;
+        }
+        Ok(())
+    }
+}
+
+// kept as a plain string in the output json
+impl Serialize for GameVersion {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for GameVersion {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(s: &str) -> GameVersion {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_game_and_patch_versions() {
+        assert_eq!(
+            version("15.4.658.1234"),
+            GameVersion {
+                major: 15,
+                minor: 4,
+                build: vec![658, 1234],
+            }
+        );
+        assert_eq!(version("15-4"), version("15.4"));
+        assert_eq!(version("15.4."), version("15.4"));
+        assert_eq!(version("15.4.658.1234").to_string(), "15.4.658.1234");
+        assert_eq!(version("15.4.658.1234").patch_name(), "15-4");
+
+        for bad in ["", "15", "15.x", "a.b.c", "15..4"] {
+            assert!(bad.parse::<GameVersion>().is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn orders_by_major_minor_then_build() {
+        assert!(version("14.24") < version("15.1"));
+        assert!(version("15.2") < version("15.10"));
+        assert!(version("15.4") < version("15.4.1"));
+        assert!(version("15.4.658") < version("15.4.700"));
+
+        assert!(version("15.4.658.1234").same_patch(&version("15.4.700")));
+        assert!(!version("15.4").same_patch(&version("15.5")));
+    }
+
+    #[test]
+    fn serializes_as_a_string() {
+        let json = serde_json::to_string(&version("15.4.658")).unwrap();
+        assert_eq!(json, "\"15.4.658\"");
+        assert_eq!(
+            serde_json::from_str::<GameVersion>(&json).unwrap(),
+            version("15.4.658")
+        );
+    }
+}
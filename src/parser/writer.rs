@@ -0,0 +1,163 @@
+use std::io::{self, Write};
+
+use crate::parser::block::{Block, BlockWriter};
+use crate::parser::header::{RoflHeader, SIGNATURE_SIZE};
+
+// builds a ROFL2 replay: header, chunks, then the signature and metadata trailer
+pub struct RoflWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> RoflWriter<W> {
+    pub fn new(mut writer: W, header: &RoflHeader) -> io::Result<Self> {
+        header.write(&mut writer)?;
+
+        Ok(RoflWriter { writer })
+    }
+
+    pub fn write_chunk(
+        &mut self,
+        id: u32,
+        type_: u8,
+        id_2: u32,
+        blocks: &[Block],
+    ) -> io::Result<()> {
+        let mut block_writer = BlockWriter::default();
+        for block in blocks {
+            block_writer.write_block(block);
+        }
+
+        self.write_chunk_payload(id, type_, id_2, &block_writer.finish())
+    }
+
+    // `payload` is the uncompressed chunk, as returned by ChunkParser
+    pub fn write_chunk_payload(
+        &mut self,
+        id: u32,
+        type_: u8,
+        id_2: u32,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let compressed_payload = zstd::bulk::compress(payload, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+
+        self.writer.write_all(&id.to_le_bytes())?;
+        self.writer.write_all(&[type_])?;
+        self.writer.write_all(&id_2.to_le_bytes())?;
+        self.writer
+            .write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.writer
+            .write_all(&(compressed_payload.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&compressed_payload)
+    }
+
+    // the signature won't match the new content, it's only there to keep the layout
+    pub fn finish(mut self, signature: &[u8], metadata: &[u8]) -> io::Result<W> {
+        let mut signature = signature.to_vec();
+        signature.resize(SIGNATURE_SIZE as usize, 0);
+
+        self.writer.write_all(&signature)?;
+        self.writer.write_all(metadata)?;
+        self.writer
+            .write_all(&(metadata.len() as u32).to_le_bytes())?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::borrow::Cow;
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::parser::block::BlockParser;
+    use crate::parser::chunk::ChunkParser;
+    use crate::parser::header::{ROFL2_FORMAT_VERSION, ROFL_MAGIC};
+    use crate::parser::keyframe::KEYFRAME_CHUNK_TYPE;
+
+    pub const METADATA: &[u8] = br#"{"gameLength": 1800000, "statsJson": "[]"}"#;
+
+    pub fn block(timestamp: f32, packet_id: u16, param: u32) -> Block<'static> {
+        let payload = vec![packet_id as u8; 4];
+        Block {
+            length: payload.len() as u32,
+            timestamp,
+            packet_id,
+            param,
+            payload: Cow::Owned(payload),
+        }
+    }
+
+    pub fn header(game_version: &str) -> RoflHeader {
+        RoflHeader {
+            magic: ROFL_MAGIC,
+            format_version: ROFL2_FORMAT_VERSION,
+            unknown: [0; 8],
+            game_version: game_version.to_string(),
+            payload_offset: 0,
+            payload_end: 0,
+            signature: Vec::new(),
+            metadata_offset: 0,
+            metadata_len: 0,
+            legacy: None,
+        }
+    }
+
+    // a ROFL2 replay holding `chunks`, as (id, type, id_2, blocks)
+    pub fn replay(chunks: &[(u32, u8, u32, Vec<Block>)]) -> Vec<u8> {
+        let mut writer = RoflWriter::new(Vec::new(), &header("15.4.658.1234")).unwrap();
+        for (id, type_, id_2, blocks) in chunks {
+            writer.write_chunk(*id, *type_, *id_2, blocks).unwrap();
+        }
+        writer.finish(&[0xAA; 4], METADATA).unwrap()
+    }
+
+    #[test]
+    fn writer_round_trips_through_chunk_parser() {
+        let chunks = vec![
+            (1, 0x1, 0, vec![block(0.5, 0x10, 0x40000001)]),
+            (
+                1,
+                KEYFRAME_CHUNK_TYPE,
+                2,
+                vec![block(30.0, 0x20, 0x40000001), block(30.0, 0x20, 0x40000002)],
+            ),
+            (
+                2,
+                0x1,
+                1,
+                vec![
+                    block(30.0, 0x10, 0x40000001),
+                    block(31.25, 0x30, 0x40000003),
+                ],
+            ),
+        ];
+        let raw = replay(&chunks);
+
+        let mut reader = Cursor::new(raw);
+        let header = RoflHeader::read(&mut reader).unwrap();
+        assert_eq!(header.game_version, "15.4.658.1234");
+        assert_eq!(header.read_metadata(&mut reader).unwrap(), METADATA);
+        assert_eq!(header.signature[..4], [0xAA; 4]);
+        assert!(header.signature[4..].iter().all(|&b| b == 0));
+
+        let mut chunk_parser = ChunkParser::new(reader).unwrap();
+        for (id, type_, id_2, blocks) in &chunks {
+            let chunk = chunk_parser.next_chunk().unwrap().unwrap();
+            assert_eq!((chunk.id, chunk.type_, chunk.id_2), (*id, *type_, *id_2));
+
+            let payload = chunk.payload.unwrap();
+            let mut block_parser = BlockParser::new(&payload);
+            for expected in blocks {
+                let block = block_parser.next_block().unwrap().unwrap();
+                assert_eq!(block.timestamp, expected.timestamp);
+                assert_eq!(block.packet_id, expected.packet_id);
+                assert_eq!(block.param, expected.param);
+                assert_eq!(block.payload, expected.payload);
+            }
+            assert!(block_parser.next_block().unwrap().is_none());
+        }
+        assert!(chunk_parser.next_chunk().unwrap().is_none());
+    }
+}
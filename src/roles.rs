@@ -0,0 +1,459 @@
+use std::collections::HashMap;
+
+use crate::emulator::packet::PathPacket;
+use crate::parser::metadata::{Metadata, PositionSource};
+use crate::parser::util::point_dist;
+
+// Top, Jungle, Mid, Adc, Support, same order as the metadata fallback
+const ROLES: [&str; 5] = ["Top", "Jungle", "Mid", "Adc", "Support"];
+
+// laning phase: minions reach the lanes around 1:30, lanes mix up after ~10 minutes
+const EARLY_START: f32 = 90.0;
+const EARLY_END: f32 = 600.0;
+const SAMPLE_INTERVAL: f32 = 1.0;
+
+// Summoner's Rift spans ~0..14800 on both axes, blue base bottom left
+const LANE_WIDTH: f32 = 2000.0;
+const MID_WIDTH: f32 = 1500.0;
+const CAMP_RADIUS: f32 = 1000.0;
+
+const JUNGLE_CAMPS: [(f32, f32); 12] = [
+    // blue side: blue buff, gromp, wolves, raptors, red buff, krugs
+    (3821.0, 8101.0),
+    (2288.0, 8448.0),
+    (3783.0, 6495.0),
+    (7061.0, 5325.0),
+    (7762.0, 4011.0),
+    (8394.0, 2641.0),
+    // red side
+    (11031.0, 6990.0),
+    (12703.0, 6443.0),
+    (11008.0, 8387.0),
+    (7852.0, 9433.0),
+    (7066.0, 10975.0),
+    (6317.0, 12146.0),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Area {
+    Top,
+    Mid,
+    Bot,
+    Camp,
+    Other,
+}
+
+fn area(x: f32, y: f32) -> Area {
+    let top = (x < LANE_WIDTH && y > 5000.0) || (y > 14800.0 - LANE_WIDTH && x < 9800.0);
+    let bot = (y < LANE_WIDTH && x > 5000.0) || (x > 14800.0 - LANE_WIDTH && y < 9800.0);
+    let mid = (x - y).abs() < MID_WIDTH && x > 3000.0 && x < 11800.0;
+
+    if top {
+        Area::Top
+    } else if bot {
+        Area::Bot
+    } else if mid {
+        Area::Mid
+    } else if JUNGLE_CAMPS
+        .iter()
+        .any(|&camp| point_dist((x, y), camp) < CAMP_RADIUS)
+    {
+        Area::Camp
+    } else {
+        Area::Other
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct Presence {
+    top: f32,
+    mid: f32,
+    bot: f32,
+    camp: f32,
+    samples: f32,
+}
+
+// share of the early game samples each player spent in each area, by player index
+fn early_presence(
+    path_packets: &[PathPacket],
+    player_id_start: u32,
+    player_count: usize,
+) -> Vec<Presence> {
+    let mut presence = vec![Presence::default(); player_count];
+    let mut players_path_state: HashMap<u32, &PathPacket> = HashMap::new();
+
+    let mut packets = path_packets.iter().peekable();
+    let mut t = EARLY_START;
+    while t <= EARLY_END {
+        while let Some(packet) = packets.next_if(|packet| packet.timestamp <= t) {
+            if packet.id >= player_id_start && packet.id < player_id_start + player_count as u32 {
+                players_path_state.insert(packet.id, packet);
+            }
+        }
+
+        for (id, path) in &players_path_state {
+            let (x, y) = path.get_pos(t);
+            let player = &mut presence[(id - player_id_start) as usize];
+            player.samples += 1.0;
+            match area(x, y) {
+                Area::Top => player.top += 1.0,
+                Area::Mid => player.mid += 1.0,
+                Area::Bot => player.bot += 1.0,
+                Area::Camp => player.camp += 1.0,
+                Area::Other => {}
+            }
+        }
+
+        t += SAMPLE_INTERVAL;
+    }
+
+    for player in &mut presence {
+        if player.samples > 0.0 {
+            player.top /= player.samples;
+            player.mid /= player.samples;
+            player.bot /= player.samples;
+            player.camp /= player.samples;
+        }
+    }
+
+    presence
+}
+
+// share of the team's neutral minion kills, the smite user takes nearly all of them.
+// summoner spells aren't decoded, so smite usage is approximated from the end of game
+// NEUTRAL_MINIONS_KILLED stat rather than from anything seen in the early window
+fn smite_share(metadata: &Metadata, team: &[usize]) -> Vec<f32> {
+    let kills: Vec<f32> = team
+        .iter()
+        .map(|&i| {
+            metadata.players[i]
+                .stats
+                .neutral_minions_killed
+                .unwrap_or(0) as f32
+        })
+        .collect();
+    let total: f32 = kills.iter().sum();
+
+    kills
+        .iter()
+        .map(|k| if total > 0.0 { k / total } else { 0.0 })
+        .collect()
+}
+
+// lane minions relative to the best farmer of the team, splits the bot lane duo
+fn farm_share(metadata: &Metadata, team: &[usize]) -> Vec<f32> {
+    let cs: Vec<f32> = team
+        .iter()
+        .map(|&i| metadata.players[i].stats.minions_killed.unwrap_or(0) as f32)
+        .collect();
+    let max = cs.iter().cloned().fold(0.0, f32::max);
+
+    cs.iter()
+        .map(|cs| if max > 0.0 { cs / max } else { 0.5 })
+        .collect()
+}
+
+// score of each player of the team for each role, in ROLES order
+fn role_scores(metadata: &Metadata, team: &[usize], presence: &[Presence]) -> Vec<[f32; 5]> {
+    let smite = smite_share(metadata, team);
+    let farm = farm_share(metadata, team);
+
+    team.iter()
+        .enumerate()
+        .map(|(k, &i)| {
+            let p = &presence[i];
+            [
+                p.top,
+                0.5 * p.camp + 0.5 * smite[k],
+                p.mid,
+                p.bot * farm[k],
+                p.bot * (1.0 - farm[k]),
+            ]
+        })
+        .collect()
+}
+
+fn permutations(items: &mut [usize], k: usize, out: &mut Vec<Vec<usize>>) {
+    if k == items.len() {
+        out.push(items.to_vec());
+        return;
+    }
+
+    for i in k..items.len() {
+        items.swap(k, i);
+        permutations(items, k + 1, out);
+        items.swap(k, i);
+    }
+}
+
+// best role among `free_roles` for every player scored in `scores`, tries every assignment.
+// confidence is the assigned role's share of the player's scores over the free roles
+fn assign_roles(scores: &[[f32; 5]], free_roles: &[usize]) -> Vec<(usize, f32)> {
+    let mut all: Vec<Vec<usize>> = Vec::new();
+    permutations(&mut free_roles.to_vec(), 0, &mut all);
+
+    // players take the first roles of the permutation, the rest stay free
+    let total = |roles: &Vec<usize>| -> f32 {
+        scores
+            .iter()
+            .zip(roles)
+            .map(|(player_scores, &role)| player_scores[role])
+            .sum()
+    };
+
+    let best = all
+        .iter()
+        .max_by(|a, b| total(a).partial_cmp(&total(b)).unwrap())
+        .unwrap();
+
+    scores
+        .iter()
+        .zip(best)
+        .map(|(player_scores, &role)| {
+            let sum: f32 = free_roles.iter().map(|&free| player_scores[free]).sum();
+            let confidence = if sum > 0.0 {
+                player_scores[role] / sum
+            } else {
+                0.0
+            };
+            (role, confidence)
+        })
+        .collect()
+}
+
+// replaces the index based positions of `metadata` with roles inferred from the early game
+// movement. players whose position came from the replay metadata keep it, the others share
+// the roles left over. `range` is the part of the replay the path packets come from, nothing
+// is inferred when it doesn't cover the early game
+pub fn infer_roles(
+    metadata: &mut Metadata,
+    path_packets: &[PathPacket],
+    player_id_start: u32,
+    range: Option<(f32, f32)>,
+) {
+    if range.is_some_and(|(start, end)| start > EARLY_START || end < EARLY_END) {
+        return;
+    }
+
+    let presence = early_presence(path_packets, player_id_start, metadata.players.len());
+
+    for team_name in ["Blue", "Red"] {
+        let team: Vec<usize> = (0..metadata.players.len())
+            .filter(|&i| metadata.players[i].team == team_name)
+            .collect();
+        if team.len() != ROLES.len() {
+            continue;
+        }
+
+        let (guessed, known): (Vec<usize>, Vec<usize>) = team
+            .iter()
+            .copied()
+            .partition(|&i| metadata.players[i].position_source == PositionSource::Index);
+        let free_roles: Vec<usize> = (0..ROLES.len())
+            .filter(|&role| {
+                known
+                    .iter()
+                    .all(|&i| metadata.players[i].position != ROLES[role])
+            })
+            .collect();
+        // two players of the metadata on the same role, nothing sensible is left to infer
+        if guessed.is_empty() || free_roles.len() < guessed.len() {
+            continue;
+        }
+        // a player never seen moving would just take whatever role is left
+        if guessed.iter().any(|&i| presence[i].samples == 0.0) {
+            continue;
+        }
+
+        // scores are computed against the whole team, the farm and smite shares need everyone
+        let scores = role_scores(metadata, &team, &presence);
+        let guessed_scores: Vec<[f32; 5]> = team
+            .iter()
+            .zip(scores)
+            .filter(|(i, _)| guessed.contains(i))
+            .map(|(_, scores)| scores)
+            .collect();
+
+        for (&i, (role, confidence)) in guessed
+            .iter()
+            .zip(assign_roles(&guessed_scores, &free_roles))
+        {
+            let player = &mut metadata.players[i];
+            player.position = ROLES[role].to_string();
+            player.position_source = PositionSource::Inferred;
+            player.position_confidence = Some(confidence);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::metadata::{GameOutcome, Player};
+    use crate::parser::stats::PlayerStats;
+
+    const PLAYER_ID_START: u32 = 0x4000_0000;
+
+    const TOP_LANE: (f32, f32) = (1000.0, 10000.0);
+    const BOT_LANE: (f32, f32) = (10000.0, 1000.0);
+    const MID_LANE: (f32, f32) = (7400.0, 7400.0);
+    const BLUE_BUFF: (f32, f32) = JUNGLE_CAMPS[0];
+
+    #[test]
+    fn areas() {
+        assert_eq!(area(TOP_LANE.0, TOP_LANE.1), Area::Top);
+        assert_eq!(area(5000.0, 14000.0), Area::Top);
+        assert_eq!(area(BOT_LANE.0, BOT_LANE.1), Area::Bot);
+        assert_eq!(area(14000.0, 5000.0), Area::Bot);
+        assert_eq!(area(MID_LANE.0, MID_LANE.1), Area::Mid);
+        assert_eq!(area(BLUE_BUFF.0, BLUE_BUFF.1), Area::Camp);
+        // fountain
+        assert_eq!(area(500.0, 500.0), Area::Other);
+    }
+
+    #[test]
+    fn assignment_maximizes_the_total_score() {
+        // both prefer top, the second one by far
+        let scores = [[0.9, 0.0, 0.8, 0.0, 0.0], [0.9, 0.0, 0.1, 0.0, 0.0]];
+        let assigned = assign_roles(&scores, &[0, 2]);
+
+        assert_eq!(assigned[0].0, 2);
+        assert!((assigned[0].1 - 0.8 / 1.7).abs() < 1e-6);
+        assert_eq!(assigned[1].0, 0);
+        assert!((assigned[1].1 - 0.9).abs() < 1e-6);
+    }
+
+    #[test]
+    fn assignment_leaves_extra_roles_free() {
+        let scores = [[0.0, 0.2, 0.0, 0.0, 0.6]];
+        assert_eq!(assign_roles(&scores, &[1, 3, 4]), [(4, 0.75)]);
+        // no score at all, any role with no confidence
+        assert_eq!(assign_roles(&[[0.0; 5]], &[1])[0], (1, 0.0));
+    }
+
+    // (where the player stands, minions, neutral minions), in player order
+    const TEAM: [((f32, f32), u32, u32); 5] = [
+        (BOT_LANE, 200, 0),
+        (TOP_LANE, 180, 4),
+        (BOT_LANE, 30, 0),
+        (BLUE_BUFF, 40, 150),
+        (MID_LANE, 190, 8),
+    ];
+    const TEAM_ROLES: [&str; 5] = ["Adc", "Top", "Support", "Jungle", "Mid"];
+
+    fn metadata() -> Metadata {
+        let players = ["Blue", "Red"]
+            .iter()
+            .flat_map(|team| {
+                TEAM.iter()
+                    .zip(ROLES)
+                    .map(|(&(_, minions, neutral_minions), role)| Player {
+                        name: String::new(),
+                        riot_id_game_name: None,
+                        riot_id_tag_line: None,
+                        puuid: None,
+                        summoner_level: None,
+                        champion: String::new(),
+                        skin_index: None,
+                        team: team.to_string(),
+                        position: role.to_string(),
+                        position_source: PositionSource::Index,
+                        position_confidence: None,
+                        stats: PlayerStats {
+                            minions_killed: Some(minions),
+                            neutral_minions_killed: Some(neutral_minions),
+                            ..Default::default()
+                        },
+                    })
+            })
+            .collect();
+
+        Metadata {
+            version: "15.4.658.1234".parse().unwrap(),
+            game_len: 30 * 60 * 1000,
+            outcome: GameOutcome::Unknown,
+            players,
+        }
+    }
+
+    // every player standing still on its TEAM spot for the whole game
+    fn path_packets() -> Vec<PathPacket> {
+        (0..10)
+            .map(|i| PathPacket {
+                timestamp: 0.0,
+                id: PLAYER_ID_START + i as u32,
+                speed: 350.0,
+                waypoints: vec![TEAM[i % 5].0],
+            })
+            .collect()
+    }
+
+    fn positions(metadata: &Metadata) -> Vec<&str> {
+        metadata
+            .players
+            .iter()
+            .map(|player| player.position.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn roles_follow_the_early_game() {
+        let mut metadata = metadata();
+        infer_roles(&mut metadata, &path_packets(), PLAYER_ID_START, None);
+
+        assert_eq!(positions(&metadata), [TEAM_ROLES, TEAM_ROLES].concat());
+        assert!(metadata
+            .players
+            .iter()
+            .all(|player| player.position_source == PositionSource::Inferred
+                && player.position_confidence.is_some()));
+    }
+
+    #[test]
+    fn metadata_positions_are_kept() {
+        let mut metadata = metadata();
+        // the top laner is known to be the top laner, the adc can't take top anymore
+        metadata.players[1].position = "Top".to_string();
+        metadata.players[1].position_source = PositionSource::TeamPosition;
+        metadata.players[0].position = "Jungle".to_string();
+        infer_roles(&mut metadata, &path_packets(), PLAYER_ID_START, None);
+
+        assert_eq!(positions(&metadata)[..5], TEAM_ROLES);
+        assert_eq!(
+            metadata.players[1].position_source,
+            PositionSource::TeamPosition
+        );
+    }
+
+    #[test]
+    fn teams_with_unseen_players_keep_their_positions() {
+        let mut metadata = metadata();
+        let path_packets: Vec<PathPacket> = path_packets()
+            .into_iter()
+            .filter(|packet| packet.id != PLAYER_ID_START + 7)
+            .collect();
+        infer_roles(&mut metadata, &path_packets, PLAYER_ID_START, None);
+
+        assert_eq!(positions(&metadata), [TEAM_ROLES, ROLES].concat());
+        assert_eq!(metadata.players[7].position_source, PositionSource::Index);
+    }
+
+    #[test]
+    fn nothing_is_inferred_without_the_early_game() {
+        for range in [(700.0, f32::INFINITY), (0.0, 300.0)] {
+            let mut metadata = metadata();
+            infer_roles(&mut metadata, &path_packets(), PLAYER_ID_START, Some(range));
+
+            assert_eq!(positions(&metadata), [ROLES, ROLES].concat());
+        }
+
+        let mut metadata = metadata();
+        infer_roles(
+            &mut metadata,
+            &path_packets(),
+            PLAYER_ID_START,
+            Some((0.0, 1200.0)),
+        );
+        assert_eq!(positions(&metadata), [TEAM_ROLES, TEAM_ROLES].concat());
+    }
+}